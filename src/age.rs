@@ -0,0 +1,633 @@
+use crate::recipient::{Identity, Recipient};
+use crate::ssh::SshWrapped;
+use crate::stream::{self, TAG_LEN};
+use crate::{CliError, KEY_LEN};
+use base64::{
+    alphabet,
+    engine::{
+        general_purpose::STANDARD as BASE64, DecodePaddingMode, GeneralPurpose,
+        GeneralPurposeConfig,
+    },
+    Engine,
+};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::prelude::*;
+use sha2::Sha256;
+use std::io::{BufRead, Read, Write};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// Prefix of the labels used by age
+const LABEL_PREFIX: &str = "age-encryption.org/v1";
+
+/// First line of every age v1 file
+const VERSION_LINE: &[u8] = b"age-encryption.org/v1\n";
+
+const FILE_KEY_LEN: usize = 16;
+const PAYLOAD_NONCE_LEN: usize = 16;
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Columns of a stanza body line, a shorter line ending the body
+const BODY_COLUMNS: usize = 64;
+
+/// Longest header line accepted, bounding memory use while parsing
+const MAX_LINE_LEN: u64 = 4096;
+
+/// scrypt work factor (log2 N) used when encrypting with a password
+const DEFAULT_WORK_FACTOR: u8 = 18;
+
+/// Largest scrypt work factor accepted from a file, bounding the work done while decrypting
+const MAX_WORK_FACTOR: u8 = 22;
+
+const SCRYPT_SALT_LEN: usize = 16;
+const SCRYPT_SALT_LABEL: &[u8] = b"age-encryption.org/v1/scrypt";
+
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----";
+const ARMOR_COLUMNS: usize = 64;
+/// Bytes encoded on each full armor line
+const ARMOR_LINE_BYTES: usize = ARMOR_COLUMNS / 4 * 3;
+
+type FileKey = [u8; FILE_KEY_LEN];
+
+/// Unpadded, canonical base64 used throughout the age header
+const BASE64_NO_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::RequireNone),
+);
+
+/// Parsed age header
+struct Header {
+    stanzas: Vec<Stanza>,
+    /// Raw header up to and including `---`, covered by the MAC
+    authenticated: Vec<u8>,
+    mac: [u8; 32],
+}
+
+/// Recipient stanza of an age header
+enum Stanza {
+    X25519 {
+        share: [u8; KEY_LEN],
+        body: Vec<u8>,
+    },
+    Scrypt {
+        salt: [u8; SCRYPT_SALT_LEN],
+        work_factor: u8,
+        body: Vec<u8>,
+    },
+    Ssh(SshWrapped),
+    /// Stanza of a kind this implementation does not know, skipped when decrypting
+    Other,
+}
+
+impl Stanza {
+    /// Interprets the arguments and body of a stanza, rejecting malformed known kinds
+    fn from_parts(args: &[&str], body: Vec<u8>) -> Result<Self, CliError> {
+        let wrapped_len = FILE_KEY_LEN + TAG_LEN;
+        match args {
+            ["X25519", share] if body.len() == wrapped_len => Ok(Stanza::X25519 {
+                share: decode_exact(share)?,
+                body,
+            }),
+            ["scrypt", salt, work_factor] if body.len() == wrapped_len => Ok(Stanza::Scrypt {
+                salt: decode_exact(salt)?,
+                work_factor: parse_work_factor(work_factor)?,
+                body,
+            }),
+            ["ssh-ed25519", tag, share] if body.len() == wrapped_len => {
+                Ok(Stanza::Ssh(SshWrapped {
+                    tag: decode_exact(tag)?,
+                    share: Some(decode_exact(share)?),
+                    body,
+                }))
+            }
+            ["ssh-rsa", tag] if !body.is_empty() => Ok(Stanza::Ssh(SshWrapped {
+                tag: decode_exact(tag)?,
+                share: None,
+                body,
+            })),
+            ["X25519" | "scrypt" | "ssh-ed25519" | "ssh-rsa", ..] => Err(CliError::InvalidHeader),
+            _ => Ok(Stanza::Other),
+        }
+    }
+}
+
+/// Decodes an unpadded base64 argument of exactly `N` bytes
+fn decode_exact<const N: usize>(encoded: &str) -> Result<[u8; N], CliError> {
+    BASE64_NO_PAD
+        .decode(encoded)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(CliError::InvalidHeader)
+}
+
+/// Parses a scrypt work factor, a decimal number without leading zeros or sign
+fn parse_work_factor(text: &str) -> Result<u8, CliError> {
+    if text.starts_with('0') || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(CliError::InvalidHeader);
+    }
+    match text.parse() {
+        Ok(work_factor) if (1..=MAX_WORK_FACTOR).contains(&work_factor) => Ok(work_factor),
+        _ => Err(CliError::InvalidHeader),
+    }
+}
+
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8]) -> Key {
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut key)
+        .expect("key length is valid for HKDF-SHA256");
+    key
+}
+
+/// Encrypts the file key with a key used only once, hence the all zero nonce
+fn wrap(key: &Key, file_key: &FileKey) -> Result<Vec<u8>, CliError> {
+    ChaCha20Poly1305::new(key)
+        .encrypt(Nonce::from_slice(&[0; 12]), file_key.as_slice())
+        .map_err(|_| CliError::EncryptionError)
+}
+
+fn unwrap(key: &Key, body: &[u8]) -> Option<FileKey> {
+    ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(&[0; 12]), body)
+        .ok()?
+        .try_into()
+        .ok()
+}
+
+fn x25519_key(shared: &[u8], share: &[u8], recipient: &PublicKey) -> Key {
+    let salt = [share, recipient.as_bytes()].concat();
+    hkdf(&salt, shared, b"age-encryption.org/v1/X25519")
+}
+
+fn scrypt_key(password: &str, salt: &[u8], work_factor: u8) -> Result<Key, CliError> {
+    let params = scrypt::Params::new(work_factor, 8, 1, KEY_LEN)
+        .map_err(|_| CliError::KeyDerivationError)?;
+    let mut key = Key::default();
+    scrypt::scrypt(
+        password.as_bytes(),
+        &[SCRYPT_SALT_LABEL, salt].concat(),
+        &params,
+        &mut key,
+    )
+    .map_err(|_| CliError::KeyDerivationError)?;
+    Ok(key)
+}
+
+fn header_mac(file_key: &FileKey, header: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&hkdf(&[], file_key, b"header"))
+        .expect("HMAC accepts keys of any length");
+    mac.update(header);
+    mac
+}
+
+/// Builds the nonce of a payload chunk as `counter (88 bit big endian) || last flag`
+fn chunk_nonce(counter: u64, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+fn write_stanza(header: &mut Vec<u8>, args: &[&str], body: &[u8]) {
+    header.extend_from_slice(format!("-> {}\n", args.join(" ")).as_bytes());
+    let encoded = BASE64_NO_PAD.encode(body);
+    let mut lines = encoded.as_bytes().chunks(BODY_COLUMNS).peekable();
+    while let Some(line) = lines.next() {
+        header.extend_from_slice(line);
+        header.push(b'\n');
+        // A full final line is followed by an empty one, as a short line ends the body
+        if lines.peek().is_none() && line.len() == BODY_COLUMNS {
+            header.push(b'\n');
+        }
+    }
+    if encoded.is_empty() {
+        header.push(b'\n');
+    }
+}
+
+/// Streams the base64 of what is written to it between the armor lines, one line of
+/// [`ARMOR_COLUMNS`] at a time
+struct ArmorWriter<'a, W: Write> {
+    inner: &'a mut W,
+    /// Bytes of the line being filled
+    pending: Vec<u8>,
+}
+
+impl<'a, W: Write> ArmorWriter<'a, W> {
+    fn new(inner: &'a mut W) -> std::io::Result<Self> {
+        writeln!(inner, "{}", ARMOR_BEGIN)?;
+        Ok(ArmorWriter {
+            inner,
+            pending: Vec::with_capacity(ARMOR_LINE_BYTES),
+        })
+    }
+
+    /// Writes the last, possibly padded line and the end line
+    fn finish(self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            writeln!(self.inner, "{}", BASE64.encode(&self.pending))?;
+        }
+        writeln!(self.inner, "{}", ARMOR_END)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ArmorWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(ARMOR_LINE_BYTES - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+        if self.pending.len() == ARMOR_LINE_BYTES {
+            writeln!(self.inner, "{}", BASE64.encode(&self.pending))?;
+            self.pending.clear();
+        }
+        Ok(len)
+    }
+
+    /// Flushes the lines written so far, holding back an incomplete one
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encrypts `reader` into `writer` as an age v1 file, to `recipients` or with `password`
+pub fn encrypt<R: Read, W: Write>(
+    recipients: &[Recipient],
+    password: Option<&str>,
+    armor: bool,
+    reader: &mut R,
+    writer: &mut W,
+    input_name: &str,
+    output_name: &str,
+) -> Result<(), CliError> {
+    let file_key: FileKey = rand::thread_rng().gen();
+    let mut header = VERSION_LINE.to_vec();
+    for recipient in recipients {
+        let recipient = match recipient {
+            Recipient::X25519(public) => public,
+            Recipient::Ssh(ssh) => {
+                let wrapped = ssh.wrap(&file_key, &format!("{}/{}", LABEL_PREFIX, ssh.kind()))?;
+                let mut args = vec![ssh.kind().to_string(), BASE64_NO_PAD.encode(wrapped.tag)];
+                args.extend(wrapped.share.map(|share| BASE64_NO_PAD.encode(share)));
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                write_stanza(&mut header, &args, &wrapped.body);
+                continue;
+            }
+        };
+        let ephemeral = EphemeralSecret::random_from_rng(rand::thread_rng());
+        let share = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(recipient);
+        if !shared.was_contributory() {
+            return Err(CliError::KeyDecodeError("invalid public key".to_string()));
+        }
+        let body = wrap(
+            &x25519_key(shared.as_bytes(), share.as_bytes(), recipient),
+            &file_key,
+        )?;
+        write_stanza(
+            &mut header,
+            &["X25519", &BASE64_NO_PAD.encode(share.as_bytes())],
+            &body,
+        );
+    }
+    if let Some(password) = password {
+        let salt: [u8; SCRYPT_SALT_LEN] = rand::thread_rng().gen();
+        let body = wrap(
+            &scrypt_key(password, &salt, DEFAULT_WORK_FACTOR)?,
+            &file_key,
+        )?;
+        write_stanza(
+            &mut header,
+            &[
+                "scrypt",
+                &BASE64_NO_PAD.encode(salt),
+                &DEFAULT_WORK_FACTOR.to_string(),
+            ],
+            &body,
+        );
+    }
+    header.extend_from_slice(b"---");
+    let mac = header_mac(&file_key, &header).finalize().into_bytes();
+    header.extend_from_slice(format!(" {}\n", BASE64_NO_PAD.encode(mac)).as_bytes());
+
+    let write_error = |_| CliError::FileWriteError(output_name.to_string());
+    if !armor {
+        writer.write_all(&header).map_err(write_error)?;
+        return encrypt_payload(&file_key, reader, writer, input_name, output_name);
+    }
+
+    let mut armored = ArmorWriter::new(writer).map_err(write_error)?;
+    armored.write_all(&header).map_err(write_error)?;
+    encrypt_payload(&file_key, reader, &mut armored, input_name, output_name)?;
+    armored.finish().map_err(write_error)
+}
+
+fn encrypt_payload<R: Read, W: Write>(
+    file_key: &FileKey,
+    reader: &mut R,
+    writer: &mut W,
+    input_name: &str,
+    output_name: &str,
+) -> Result<(), CliError> {
+    let nonce: [u8; PAYLOAD_NONCE_LEN] = rand::thread_rng().gen();
+    let cipher = ChaCha20Poly1305::new(&hkdf(&nonce, file_key, b"payload"));
+    writer
+        .write_all(&nonce)
+        .map_err(|_| CliError::FileWriteError(output_name.to_string()))?;
+
+    let mut buf = vec![0u8; CHUNK_LEN];
+    let mut carry = None;
+    let mut counter: u64 = 0;
+    loop {
+        let (len, last) = stream::read_chunk(reader, &mut buf, &mut carry)
+            .map_err(|_| CliError::FileReadError(input_name.to_string()))?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&chunk_nonce(counter, last)), &buf[..len])
+            .map_err(|_| CliError::EncryptionError)?;
+        writer
+            .write_all(&ciphertext)
+            .map_err(|_| CliError::FileWriteError(output_name.to_string()))?;
+
+        if last {
+            break;
+        }
+        counter += 1;
+    }
+    writer
+        .flush()
+        .map_err(|_| CliError::FileWriteError(output_name.to_string()))
+}
+
+/// Reads one header line, including its line feed
+fn read_line<R: BufRead>(reader: &mut R, input_name: &str) -> Result<Vec<u8>, CliError> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .map_err(|_| CliError::FileReadError(input_name.to_string()))?;
+    match line.last() {
+        Some(b'\n') => Ok(line),
+        _ => Err(CliError::InvalidHeader),
+    }
+}
+
+fn read_header<R: BufRead>(reader: &mut R, input_name: &str) -> Result<Header, CliError> {
+    let mut raw = read_line(reader, input_name)?;
+    if raw != VERSION_LINE {
+        return Err(CliError::InvalidHeader);
+    }
+
+    let mut stanzas = Vec::new();
+    loop {
+        let line = read_line(reader, input_name)?;
+        let text =
+            std::str::from_utf8(&line[..line.len() - 1]).map_err(|_| CliError::InvalidHeader)?;
+        if let Some(mac) = text.strip_prefix("--- ") {
+            raw.extend_from_slice(b"---");
+            return Ok(Header {
+                stanzas,
+                authenticated: raw,
+                mac: decode_exact(mac)?,
+            });
+        }
+
+        let args: Vec<&str> = text
+            .strip_prefix("-> ")
+            .ok_or(CliError::InvalidHeader)?
+            .split(' ')
+            .collect();
+        if args
+            .iter()
+            .any(|arg| arg.is_empty() || !arg.bytes().all(|b| (0x21..=0x7e).contains(&b)))
+        {
+            return Err(CliError::InvalidHeader);
+        }
+
+        let mut encoded = Vec::new();
+        let mut body_lines = Vec::new();
+        loop {
+            let body_line = read_line(reader, input_name)?;
+            let columns = body_line.len() - 1;
+            if columns > BODY_COLUMNS {
+                return Err(CliError::InvalidHeader);
+            }
+            encoded.extend_from_slice(&body_line[..columns]);
+            body_lines.push(body_line);
+            if columns < BODY_COLUMNS {
+                break;
+            }
+        }
+        let body = BASE64_NO_PAD
+            .decode(&encoded)
+            .map_err(|_| CliError::InvalidHeader)?;
+        stanzas.push(Stanza::from_parts(&args, body)?);
+
+        raw.extend_from_slice(&line);
+        raw.extend(body_lines.concat());
+    }
+}
+
+/// Finds the file key in `stanzas` with one of `identities` or `password`
+fn unwrap_file_key(
+    stanzas: &[Stanza],
+    identities: &[Identity],
+    password: Option<&str>,
+) -> Result<FileKey, CliError> {
+    let has_scrypt = stanzas
+        .iter()
+        .any(|stanza| matches!(stanza, Stanza::Scrypt { .. }));
+    if has_scrypt && stanzas.len() > 1 {
+        // A password stanza must be the only one so that the file cannot be read by others
+        return Err(CliError::InvalidHeader);
+    }
+
+    if let [Stanza::Scrypt {
+        salt,
+        work_factor,
+        body,
+    }] = stanzas
+    {
+        let password = password.ok_or(CliError::PasswordRequired)?;
+        return unwrap(&scrypt_key(password, salt, *work_factor)?, body)
+            .ok_or(CliError::WrongPassword);
+    }
+    if password.is_some() {
+        return Err(CliError::IdentityRequired);
+    }
+
+    for stanza in stanzas {
+        for identity in identities {
+            let file_key = match (stanza, identity) {
+                (Stanza::X25519 { share, body }, Identity::X25519(identity)) => {
+                    let shared = identity.diffie_hellman(&PublicKey::from(*share));
+                    if !shared.was_contributory() {
+                        return Err(CliError::InvalidHeader);
+                    }
+                    let key = x25519_key(shared.as_bytes(), share, &PublicKey::from(identity));
+                    unwrap(&key, body)
+                }
+                (Stanza::Ssh(wrapped), Identity::Ssh(identity)) => identity
+                    .unwrap(wrapped, &format!("{}/{}", LABEL_PREFIX, wrapped.kind()))
+                    .and_then(|file_key| file_key.try_into().ok()),
+                _ => None,
+            };
+            if let Some(file_key) = file_key {
+                return Ok(file_key);
+            }
+        }
+    }
+    Err(CliError::NoMatchingIdentity)
+}
+
+/// Decodes an armored age file, allowing whitespace around it
+fn dearmor(data: &[u8]) -> Result<Vec<u8>, CliError> {
+    let text = std::str::from_utf8(data)
+        .map_err(|_| CliError::InvalidArmor)?
+        .trim_matches(|c: char| c.is_ascii_whitespace());
+    let lines: Vec<&str> = text
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .collect();
+    let (encoded, last) = match &lines[..] {
+        [ARMOR_BEGIN, encoded @ .., last, ARMOR_END] => (encoded, last),
+        _ => return Err(CliError::InvalidArmor),
+    };
+    if encoded.iter().any(|line| line.len() != ARMOR_COLUMNS)
+        || last.is_empty()
+        || last.len() > ARMOR_COLUMNS
+    {
+        return Err(CliError::InvalidArmor);
+    }
+    BASE64
+        .decode([encoded.concat().as_str(), last].concat())
+        .map_err(|_| CliError::InvalidArmor)
+}
+
+/// Decrypts an age v1 file, binary or armored, from `reader` into `writer`
+pub fn decrypt<R: BufRead, W: Write>(
+    identities: &[Identity],
+    password: Option<&str>,
+    reader: &mut R,
+    writer: &mut W,
+    input_name: &str,
+    output_name: &str,
+) -> Result<(), CliError> {
+    let read_error = |_| CliError::FileReadError(input_name.to_string());
+    let start = reader.fill_buf().map_err(read_error)?;
+    let armored = start
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .is_some_and(|i| start[i..].starts_with(b"-----BEGIN"));
+    if armored {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).map_err(read_error)?;
+        let binary = dearmor(&data)?;
+        return decrypt(
+            identities,
+            password,
+            &mut binary.as_slice(),
+            writer,
+            input_name,
+            output_name,
+        );
+    }
+
+    let header = read_header(reader, input_name)?;
+    let file_key = unwrap_file_key(&header.stanzas, identities, password)?;
+    header_mac(&file_key, &header.authenticated)
+        .verify_slice(&header.mac)
+        .map_err(|_| CliError::HeaderAuthError)?;
+
+    let mut nonce = [0u8; PAYLOAD_NONCE_LEN];
+    if stream::read_full(reader, &mut nonce).map_err(read_error)? < nonce.len() {
+        return Err(CliError::InvalidHeader);
+    }
+    let cipher = ChaCha20Poly1305::new(&hkdf(&nonce, &file_key, b"payload"));
+    let decrypt_chunk = |counter, last, chunk: &[u8]| {
+        cipher
+            .decrypt(Nonce::from_slice(&chunk_nonce(counter, last)), chunk)
+            .ok()
+    };
+
+    let mut buf = vec![0u8; CHUNK_LEN + TAG_LEN];
+    let mut carry = None;
+    let mut counter: u64 = 0;
+    loop {
+        let (len, at_end) = stream::read_chunk(reader, &mut buf, &mut carry).map_err(read_error)?;
+        let chunk = &buf[..len];
+        let plaintext =
+            decrypt_chunk(counter, at_end, chunk).ok_or_else(|| {
+                match decrypt_chunk(counter, !at_end, chunk) {
+                    Some(_) if at_end => CliError::Truncated,
+                    Some(_) => CliError::TrailingData,
+                    None => CliError::DecryptionError,
+                }
+            })?;
+        // Only an entirely empty payload may end with an empty chunk
+        if at_end && plaintext.is_empty() && counter > 0 {
+            return Err(CliError::DecryptionError);
+        }
+        writer
+            .write_all(&plaintext)
+            .map_err(|_| CliError::FileWriteError(output_name.to_string()))?;
+
+        if at_end {
+            break;
+        }
+        counter += 1;
+    }
+    writer
+        .flush()
+        .map_err(|_| CliError::FileWriteError(output_name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+
+    #[test]
+    fn armored_round_trip() {
+        let secret = StaticSecret::random_from_rng(rand::thread_rng());
+        let recipient = Recipient::X25519(PublicKey::from(&secret));
+        let plaintext: Vec<u8> = (0..CHUNK_LEN * 2 + 100).map(|i| i as u8).collect();
+
+        let mut armored = Vec::new();
+        encrypt(
+            &[recipient],
+            None,
+            true,
+            &mut plaintext.as_slice(),
+            &mut armored,
+            "in",
+            "out",
+        )
+        .unwrap();
+        let text = std::str::from_utf8(&armored).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.first(), Some(&ARMOR_BEGIN));
+        assert_eq!(lines.last(), Some(&ARMOR_END));
+        let body = &lines[1..lines.len() - 1];
+        assert!(body[..body.len() - 1]
+            .iter()
+            .all(|line| line.len() == ARMOR_COLUMNS));
+
+        let mut decrypted = Vec::new();
+        decrypt(
+            &[Identity::X25519(secret)],
+            None,
+            &mut armored.as_slice(),
+            &mut decrypted,
+            "in",
+            "out",
+        )
+        .unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+}
@@ -0,0 +1,274 @@
+use crate::header::Stanza;
+use crate::keyring::Keyring;
+use crate::recipient::{self, STANZA_KEY};
+use crate::{CliError, KEY_LEN};
+use chacha20poly1305::Key;
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+/// Environment variable holding the socket of a running agent
+pub const SOCKET_ENV: &str = "CRYPT_AGENT_SOCK";
+
+/// Default number of seconds without requests after which the agent exits
+pub const DEFAULT_TIMEOUT: u64 = 15 * 60;
+
+/// Wrap a file key with a named key, the body being the file key followed by the name
+const OP_WRAP: u8 = 1;
+/// Unwrap the file key from the body of a key stanza
+const OP_UNWRAP: u8 = 2;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_NAME: u8 = 1;
+const STATUS_NO_KEY: u8 = 2;
+const STATUS_BAD_REQUEST: u8 = 3;
+const STATUS_DENIED: u8 = 4;
+
+/// How long a client may take to send its request once connected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval at which the idle timeout is checked while no client connects
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Writes a request or response, one per connection
+///
+/// ```text
+/// op or status 1 byte
+/// body len     2 bytes  little endian
+/// body         body len bytes
+/// ```
+fn write_message<W: Write>(writer: &mut W, code: u8, body: &[u8]) -> std::io::Result<()> {
+    let len = u16::try_from(body.len()).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+    writer.write_all(&[&[code], len.to_le_bytes().as_slice(), body].concat())?;
+    writer.flush()
+}
+
+fn read_message<R: Read>(reader: &mut R) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut prefix = [0u8; 3];
+    reader.read_exact(&mut prefix)?;
+    let mut body = vec![0u8; u16::from_le_bytes([prefix[1], prefix[2]]) as usize];
+    reader.read_exact(&mut body)?;
+    Ok((prefix[0], body))
+}
+
+/// Answers a request with the keys of `keyring`, which never leave the agent
+fn answer(keyring: &Keyring, op: u8, body: Vec<u8>) -> (u8, Vec<u8>) {
+    match op {
+        OP_WRAP if body.len() > KEY_LEN => {
+            let (file_key, name) = body.split_at(KEY_LEN);
+            let key = match std::str::from_utf8(name).map(|name| keyring.get(name)) {
+                Ok(Ok(key)) => key,
+                _ => return (STATUS_UNKNOWN_NAME, Vec::new()),
+            };
+            match recipient::wrap_key(Key::from_slice(file_key), &key) {
+                Ok(stanza) => (STATUS_OK, stanza.body),
+                Err(_) => (STATUS_BAD_REQUEST, Vec::new()),
+            }
+        }
+        OP_UNWRAP => {
+            let stanza = Stanza {
+                kind: STANZA_KEY,
+                body,
+            };
+            match keyring
+                .keys()
+                .iter()
+                .find_map(|key| recipient::unwrap_key(&stanza, key))
+            {
+                Some(file_key) => (STATUS_OK, file_key.to_vec()),
+                None => (STATUS_NO_KEY, Vec::new()),
+            }
+        }
+        _ => (STATUS_BAD_REQUEST, Vec::new()),
+    }
+}
+
+/// User id of the process at the other end of `stream`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (result == 0).then_some(cred.uid)
+}
+
+/// User id of the process at the other end of `stream`
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn peer_uid(stream: &UnixStream) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let (mut uid, mut gid) = (0, 0);
+    let result = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    (result == 0).then_some(uid)
+}
+
+/// Serves one request, refusing clients running as another user
+#[cfg(unix)]
+fn handle(keyring: &Keyring, mut stream: UnixStream) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    if peer_uid(&stream) != Some(unsafe { libc::geteuid() }) {
+        return write_message(&mut stream, STATUS_DENIED, &[]);
+    }
+    let (op, body) = read_message(&mut stream)?;
+    let (status, response) = answer(keyring, op, body);
+    write_message(&mut stream, status, &response)
+}
+
+/// Socket the agent answers requests on
+#[cfg(unix)]
+pub type Listener = UnixListener;
+
+#[cfg(not(unix))]
+pub enum Listener {}
+
+/// Creates `socket`, accessible to the current user only
+#[cfg(unix)]
+pub fn bind(socket: &str) -> Result<Listener, CliError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let socket_error = |_| CliError::AgentSocketError(socket.to_string());
+    let listener = UnixListener::bind(socket).map_err(socket_error)?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))
+        .and_then(|_| listener.set_nonblocking(true))
+        .map_err(|error| {
+            let _ = std::fs::remove_file(socket);
+            socket_error(error)
+        })?;
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+pub fn bind(socket: &str) -> Result<Listener, CliError> {
+    Err(CliError::AgentSocketError(socket.to_string()))
+}
+
+/// Forks the agent into the background like `ssh-agent`, returning `true` in the parent,
+/// which should exit, and `false` in the child. The child leaves the session of the terminal
+/// and has its standard streams on `/dev/null` so that `eval "$(crypt agent)"` returns.
+#[cfg(unix)]
+pub fn detach() -> Result<bool, CliError> {
+    use std::os::unix::io::AsRawFd;
+
+    let null = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|_| CliError::AgentDetachError)?;
+    // Nothing buffered may be written twice once both processes exit
+    let _ = std::io::stdout().flush();
+    match unsafe { libc::fork() } {
+        -1 => return Err(CliError::AgentDetachError),
+        0 => {}
+        _ => return Ok(true),
+    }
+    unsafe {
+        libc::setsid();
+        for fd in 0..=2 {
+            libc::dup2(null.as_raw_fd(), fd);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(not(unix))]
+pub fn detach() -> Result<bool, CliError> {
+    Err(CliError::AgentDetachError)
+}
+
+/// Answers requests on `listener` with the keys of `keyring` until no request arrives for
+/// `timeout`, then removes `socket`
+#[cfg(unix)]
+pub fn serve(keyring: &Keyring, listener: Listener, socket: &str, timeout: Duration) {
+    let mut last_request = std::time::Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let _ = handle(keyring, stream);
+                last_request = std::time::Instant::now();
+            }
+            Err(_) if last_request.elapsed() >= timeout => break,
+            Err(_) => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+    let _ = std::fs::remove_file(socket);
+}
+
+#[cfg(not(unix))]
+pub fn serve(_keyring: &Keyring, listener: Listener, _socket: &str, _timeout: Duration) {
+    match listener {}
+}
+
+/// Client of a running agent
+pub struct Agent {
+    socket: String,
+}
+
+impl Agent {
+    /// The agent whose socket is in [`SOCKET_ENV`], if any
+    pub fn from_env() -> Option<Agent> {
+        match std::env::var(SOCKET_ENV) {
+            Ok(socket) if !socket.is_empty() => Some(Agent { socket }),
+            _ => None,
+        }
+    }
+
+    #[cfg(unix)]
+    fn request(&self, op: u8, body: &[u8]) -> Result<(u8, Vec<u8>), CliError> {
+        let unavailable = |_| CliError::AgentUnavailable(self.socket.clone());
+        let mut stream = UnixStream::connect(&self.socket).map_err(unavailable)?;
+        stream
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .map_err(unavailable)?;
+        write_message(&mut stream, op, body).map_err(unavailable)?;
+        match read_message(&mut stream).map_err(unavailable)? {
+            (STATUS_DENIED, _) => Err(CliError::AgentDenied),
+            response => Ok(response),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn request(&self, _op: u8, _body: &[u8]) -> Result<(u8, Vec<u8>), CliError> {
+        Err(CliError::AgentUnavailable(self.socket.clone()))
+    }
+
+    /// Wraps `file_key` with the key called `name` in the keyring of the agent
+    pub fn wrap(&self, file_key: &Key, name: &str) -> Result<Stanza, CliError> {
+        match self.request(OP_WRAP, &[file_key.as_slice(), name.as_bytes()].concat())? {
+            (STATUS_OK, body) => Ok(Stanza {
+                kind: STANZA_KEY,
+                body,
+            }),
+            (STATUS_UNKNOWN_NAME, _) => Err(CliError::KeyNameNotFound(name.to_string())),
+            _ => Err(CliError::AgentUnavailable(self.socket.clone())),
+        }
+    }
+
+    /// Unwraps the file key from `stanza` if it was wrapped with a key in the keyring of
+    /// the agent
+    pub fn unwrap(&self, stanza: &Stanza) -> Result<Option<Key>, CliError> {
+        if stanza.kind != STANZA_KEY {
+            return Ok(None);
+        }
+        match self.request(OP_UNWRAP, &stanza.body)? {
+            (STATUS_OK, body) if body.len() == KEY_LEN => Ok(Some(*Key::from_slice(&body))),
+            (STATUS_NO_KEY, _) => Ok(None),
+            _ => Err(CliError::AgentUnavailable(self.socket.clone())),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::stream::NONCE_OVERHEAD;
+use crate::CliError;
+use aes_gcm::Aes256Gcm;
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Nonce, Payload},
+    ChaCha20Poly1305, Key, XChaCha20Poly1305,
+};
+use clap::ArgEnum;
+
+/// AEAD used for the payload, with the nonce passed as a slice so that
+/// ciphers with different nonce sizes can be used interchangeably
+pub trait PayloadCipher {
+    fn encrypt(&self, nonce: &[u8], payload: Payload) -> Result<Vec<u8>, CliError>;
+
+    fn decrypt(&self, nonce: &[u8], payload: Payload) -> Result<Vec<u8>, CliError>;
+}
+
+impl<A: Aead> PayloadCipher for A {
+    fn encrypt(&self, nonce: &[u8], payload: Payload) -> Result<Vec<u8>, CliError> {
+        Aead::encrypt(self, Nonce::<A>::from_slice(nonce), payload)
+            .map_err(|_| CliError::EncryptionError)
+    }
+
+    fn decrypt(&self, nonce: &[u8], payload: Payload) -> Result<Vec<u8>, CliError> {
+        Aead::decrypt(self, Nonce::<A>::from_slice(nonce), payload)
+            .map_err(|_| CliError::DecryptionError)
+    }
+}
+
+/// AEAD algorithm used for the payload
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherId {
+    /// ChaCha20Poly1305 with 12 byte nonces
+    #[clap(name = "chacha20poly1305")]
+    ChaCha20Poly1305,
+    /// XChaCha20Poly1305 with 24 byte nonces
+    #[clap(name = "xchacha20poly1305")]
+    XChaCha20Poly1305,
+    /// AES-256-GCM with 12 byte nonces
+    #[clap(name = "aes256gcm")]
+    Aes256Gcm,
+    /// AES-256-GCM-SIV with 12 byte nonces
+    #[clap(name = "aes256gcmsiv")]
+    Aes256GcmSiv,
+}
+
+impl CipherId {
+    pub fn id(self) -> u8 {
+        match self {
+            CipherId::ChaCha20Poly1305 => 1,
+            CipherId::XChaCha20Poly1305 => 2,
+            CipherId::Aes256Gcm => 3,
+            CipherId::Aes256GcmSiv => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, CliError> {
+        match id {
+            1 => Ok(CipherId::ChaCha20Poly1305),
+            2 => Ok(CipherId::XChaCha20Poly1305),
+            3 => Ok(CipherId::Aes256Gcm),
+            4 => Ok(CipherId::Aes256GcmSiv),
+            id => Err(CliError::UnsupportedCipher(id)),
+        }
+    }
+
+    /// Length of the AEAD nonce
+    pub fn nonce_len(self) -> usize {
+        match self {
+            CipherId::ChaCha20Poly1305 | CipherId::Aes256Gcm | CipherId::Aes256GcmSiv => 12,
+            CipherId::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    /// Length of the nonce prefix stored in the header, the rest of the nonce
+    /// being taken by the chunk counter. See [`crate::new_header`] for why this
+    /// limits each payload key to a single file.
+    pub fn nonce_prefix_len(self) -> usize {
+        self.nonce_len() - NONCE_OVERHEAD
+    }
+
+    /// Creates the cipher keyed with `key`
+    pub fn new_cipher(self, key: &Key) -> Box<dyn PayloadCipher> {
+        match self {
+            CipherId::ChaCha20Poly1305 => Box::new(ChaCha20Poly1305::new(key)),
+            CipherId::XChaCha20Poly1305 => Box::new(XChaCha20Poly1305::new(key)),
+            CipherId::Aes256Gcm => Box::new(Aes256Gcm::new(key)),
+            CipherId::Aes256GcmSiv => Box::new(Aes256GcmSiv::new(key)),
+        }
+    }
+}
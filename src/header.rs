@@ -0,0 +1,351 @@
+use crate::cipher::CipherId;
+use crate::kdf::Argon2Params;
+use crate::keyfile::KEY_ID_LEN;
+use crate::stream::{CHUNK_LEN, MAX_CHUNK_LEN};
+use crate::CliError;
+
+/// Magic bytes at the start of every `.crypt` file
+pub const MAGIC: [u8; 4] = *b"CRPT";
+
+/// Current version of the file format
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Length of the fixed part of the header (magic, version, cipher, kdf, flags, chunk length,
+/// key id, kdf params length)
+pub const FIXED_LEN: usize = MAGIC.len() + 1 + 1 + 1 + 2 + 4 + KEY_ID_LEN + 2;
+
+/// Whether `data` predates the header, i.e. is a bare `nonce || ciphertext`
+pub fn is_legacy(data: &[u8]) -> bool {
+    !data.starts_with(&MAGIC)
+}
+
+/// Key derivation used to obtain the payload key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Kdf {
+    /// The key is used as given
+    None,
+    /// The key is derived from a password with Argon2id
+    Argon2id(Argon2Params),
+}
+
+impl Kdf {
+    fn id(&self) -> u8 {
+        match self {
+            Kdf::None => 0,
+            Kdf::Argon2id(_) => 1,
+        }
+    }
+
+    fn params(&self) -> Vec<u8> {
+        match self {
+            Kdf::None => Vec::new(),
+            Kdf::Argon2id(params) => params.to_bytes(),
+        }
+    }
+
+    fn from_parts(id: u8, params: &[u8]) -> Result<Self, CliError> {
+        match id {
+            0 if params.is_empty() => Ok(Kdf::None),
+            0 => Err(CliError::InvalidHeader),
+            1 => Ok(Kdf::Argon2id(Argon2Params::from_bytes(params)?)),
+            id => Err(CliError::UnsupportedKdf(id)),
+        }
+    }
+}
+
+/// Flag marking a header that carries recipient stanzas followed by a header MAC
+pub const FLAG_RECIPIENTS: u16 = 0x0001;
+
+/// Flags understood by this version
+const KNOWN_FLAGS: u16 = FLAG_RECIPIENTS;
+
+/// Length of the header MAC
+pub const MAC_LEN: usize = 32;
+
+/// Copy of the payload key wrapped for one recipient
+///
+/// ```text
+/// kind     1 byte
+/// body len 2 bytes  little endian
+/// body     body len bytes
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stanza {
+    pub kind: u8,
+    pub body: Vec<u8>,
+}
+
+/// Header of a `.crypt` file
+///
+/// ```text
+/// magic      4 bytes  "CRPT"
+/// version    1 byte
+/// cipher     1 byte
+/// kdf        1 byte
+/// flags      2 bytes  little endian
+/// chunk len  4 bytes  little endian, plaintext bytes per chunk
+/// key id     8 bytes  id of the payload key (see [`crate::keyfile::key_id`])
+/// params len 2 bytes  little endian
+/// params     params len bytes
+/// nonce      cipher nonce prefix length bytes
+/// ```
+///
+/// With [`FLAG_RECIPIENTS`] set, the payload key is random and the above is
+/// followed by the number of stanzas (2 bytes, little endian), the stanzas
+/// and a MAC over everything before it (see [`crate::recipient::header_mac`]).
+///
+/// The header is followed by the chunked payload (see [`crate::stream::Stream`]).
+/// Everything up to the stanzas is passed to the cipher as associated data of
+/// every chunk, so any tampering with it makes decryption fail, while stanzas
+/// can be rewritten without touching the payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub cipher: CipherId,
+    pub kdf: Kdf,
+    pub flags: u16,
+    pub chunk_len: u32,
+    pub key_id: [u8; KEY_ID_LEN],
+    pub nonce_prefix: Vec<u8>,
+    pub stanzas: Vec<Stanza>,
+    pub mac: [u8; MAC_LEN],
+}
+
+impl Header {
+    pub fn new(
+        cipher: CipherId,
+        kdf: Kdf,
+        key_id: [u8; KEY_ID_LEN],
+        nonce_prefix: Vec<u8>,
+    ) -> Self {
+        Header {
+            cipher,
+            kdf,
+            flags: 0,
+            chunk_len: CHUNK_LEN,
+            key_id,
+            nonce_prefix,
+            stanzas: Vec::new(),
+            mac: [0; MAC_LEN],
+        }
+    }
+
+    /// Makes the payload key travel in `stanzas`, each wrapping it for one recipient
+    pub fn set_stanzas(&mut self, stanzas: Vec<Stanza>) {
+        self.flags |= FLAG_RECIPIENTS;
+        self.stanzas = stanzas;
+    }
+
+    /// Whether the payload key travels in stanzas
+    pub fn has_recipients(&self) -> bool {
+        self.flags & FLAG_RECIPIENTS != 0
+    }
+
+    /// Serializes the part of the header passed to the cipher as associated data
+    pub fn aad(&self) -> Vec<u8> {
+        let params = self.kdf.params();
+        let mut bytes = Vec::with_capacity(FIXED_LEN + params.len() + self.nonce_prefix.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.push(self.cipher.id());
+        bytes.push(self.kdf.id());
+        bytes.extend_from_slice(&self.flags.to_le_bytes());
+        bytes.extend_from_slice(&self.chunk_len.to_le_bytes());
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(&(params.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&params);
+        bytes.extend_from_slice(&self.nonce_prefix);
+        bytes
+    }
+
+    /// Serializes the header up to, but excluding, the MAC
+    pub fn to_bytes_unauthenticated(&self) -> Vec<u8> {
+        let mut bytes = self.aad();
+        if self.has_recipients() {
+            bytes.extend_from_slice(&(self.stanzas.len() as u16).to_le_bytes());
+            for stanza in &self.stanzas {
+                bytes.push(stanza.kind);
+                bytes.extend_from_slice(&(stanza.body.len() as u16).to_le_bytes());
+                bytes.extend_from_slice(&stanza.body);
+            }
+        }
+        bytes
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.to_bytes_unauthenticated();
+        if self.has_recipients() {
+            bytes.extend_from_slice(&self.mac);
+        }
+        bytes
+    }
+
+    /// Returns how many more bytes are needed to complete the header starting `data`,
+    /// zero once it is complete
+    pub fn missing_len(data: &[u8]) -> Result<usize, CliError> {
+        if data.len() < FIXED_LEN {
+            return Ok(FIXED_LEN - data.len());
+        }
+        if data[..MAGIC.len()] != MAGIC {
+            return Err(CliError::InvalidHeader);
+        }
+
+        let version = data[4];
+        if version != FORMAT_VERSION {
+            return Err(CliError::UnsupportedVersion(version, FORMAT_VERSION));
+        }
+
+        let cipher = CipherId::from_id(data[5])?;
+        let flags = u16::from_le_bytes([data[7], data[8]]);
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(CliError::UnsupportedFlags(flags));
+        }
+        let params_len = u16::from_le_bytes([data[FIXED_LEN - 2], data[FIXED_LEN - 1]]) as usize;
+        let mut len = FIXED_LEN + params_len + cipher.nonce_prefix_len();
+        if flags & FLAG_RECIPIENTS == 0 {
+            return Ok(len.saturating_sub(data.len()));
+        }
+
+        // Walk the stanzas as far as the data goes
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]) as usize;
+        len += 2;
+        if data.len() < len {
+            return Ok(len - data.len());
+        }
+        for _ in 0..u16_at(len - 2) {
+            len += 3;
+            if data.len() < len {
+                return Ok(len - data.len());
+            }
+            len += u16_at(len - 2);
+        }
+        len += MAC_LEN;
+        Ok(len.saturating_sub(data.len()))
+    }
+
+    /// Parses the header at the start of `data`, returning it along with the remaining data
+    pub fn parse(data: &[u8]) -> Result<(Header, &[u8]), CliError> {
+        if Header::missing_len(data)? > 0 {
+            return Err(CliError::InvalidHeader);
+        }
+
+        let cipher = CipherId::from_id(data[5])?;
+        let kdf_id = data[6];
+        let flags = u16::from_le_bytes([data[7], data[8]]);
+        let chunk_len = u32::from_le_bytes([data[9], data[10], data[11], data[12]]);
+        if chunk_len == 0 || chunk_len > MAX_CHUNK_LEN {
+            return Err(CliError::InvalidHeader);
+        }
+        let key_id = data[13..13 + KEY_ID_LEN].try_into().unwrap();
+
+        let params_len = u16::from_le_bytes([data[FIXED_LEN - 2], data[FIXED_LEN - 1]]) as usize;
+        let params_end = FIXED_LEN + params_len;
+        let kdf = Kdf::from_parts(kdf_id, &data[FIXED_LEN..params_end])?;
+        let mut pos = params_end + cipher.nonce_prefix_len();
+        let nonce_prefix = data[params_end..pos].to_vec();
+
+        let mut stanzas = Vec::new();
+        let mut mac = [0; MAC_LEN];
+        if flags & FLAG_RECIPIENTS != 0 {
+            let count = u16::from_le_bytes([data[pos], data[pos + 1]]);
+            pos += 2;
+            for _ in 0..count {
+                let body_len = u16::from_le_bytes([data[pos + 1], data[pos + 2]]) as usize;
+                stanzas.push(Stanza {
+                    kind: data[pos],
+                    body: data[pos + 3..pos + 3 + body_len].to_vec(),
+                });
+                pos += 3 + body_len;
+            }
+            mac.copy_from_slice(&data[pos..pos + MAC_LEN]);
+            pos += MAC_LEN;
+        }
+
+        let header = Header {
+            cipher,
+            kdf,
+            flags,
+            chunk_len,
+            key_id,
+            nonce_prefix,
+            stanzas,
+            mac,
+        };
+        Ok((header, &data[pos..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header::new(
+            CipherId::XChaCha20Poly1305,
+            Kdf::None,
+            [7; KEY_ID_LEN],
+            vec![1; CipherId::XChaCha20Poly1305.nonce_prefix_len()],
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let header = header();
+        let mut data = header.to_bytes();
+        data.extend_from_slice(b"payload");
+        let (parsed, rest) = Header::parse(&data).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(rest, b"payload");
+    }
+
+    #[test]
+    fn round_trip_with_stanzas() {
+        let mut header = header();
+        header.kdf = Kdf::Argon2id(Argon2Params::new(1024, 1).unwrap());
+        header.set_stanzas(vec![
+            Stanza {
+                kind: 2,
+                body: vec![3; 40],
+            },
+            Stanza {
+                kind: 1,
+                body: Vec::new(),
+            },
+        ]);
+        header.mac = [9; MAC_LEN];
+        let data = header.to_bytes();
+        assert_eq!(Header::missing_len(&data[..data.len() - 1]).unwrap(), 1);
+        let (parsed, rest) = Header::parse(&data).unwrap();
+        assert_eq!(parsed, header);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut data = header().to_bytes();
+        data[0] = b'X';
+        assert!(matches!(Header::parse(&data), Err(CliError::InvalidHeader)));
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut data = header().to_bytes();
+        data[4] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            Header::parse(&data),
+            Err(CliError::UnsupportedVersion(..))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        let data = header().to_bytes();
+        assert!(Header::parse(&data[..data.len() - 1]).is_err());
+        assert!(Header::parse(&data[..FIXED_LEN - 1]).is_err());
+
+        let mut data = header().to_bytes();
+        data[9..13].copy_from_slice(&(MAX_CHUNK_LEN + 1).to_le_bytes());
+        assert!(Header::parse(&data).is_err());
+        data[9..13].copy_from_slice(&0u32.to_le_bytes());
+        assert!(Header::parse(&data).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{CliError, KEY_LEN};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::Key;
+use rand::prelude::*;
+
+/// Default Argon2id memory cost in KiB
+pub const DEFAULT_MEMORY_COST: u32 = 64 * 1024;
+
+/// Default Argon2id number of iterations
+pub const DEFAULT_TIME_COST: u32 = 3;
+
+/// Largest memory cost accepted from a header, bounding memory use while decrypting
+const MAX_MEMORY_COST: u32 = 4 * 1024 * 1024;
+
+/// Largest number of iterations accepted from a header, bounding the work done before the
+/// header MAC can be checked
+const MAX_TIME_COST: u32 = 64;
+
+/// Largest number of lanes accepted from a header
+const MAX_PARALLELISM: u32 = 16;
+
+const PARALLELISM: u32 = 1;
+const SALT_LEN: usize = 16;
+
+/// Length of the encoded parameters (memory cost, time cost, parallelism, salt)
+const ENCODED_LEN: usize = 4 + 4 + 4 + SALT_LEN;
+
+/// Parameters of an Argon2id key derivation, stored in the file header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Argon2Params {
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+    pub salt: [u8; SALT_LEN],
+}
+
+impl Argon2Params {
+    /// Creates parameters with the given costs and a random salt, refusing costs a header
+    /// could not be read back with
+    pub fn new(memory_cost: u32, time_cost: u32) -> Result<Self, CliError> {
+        let params = Argon2Params {
+            memory_cost,
+            time_cost,
+            parallelism: PARALLELISM,
+            salt: rand::thread_rng().gen(),
+        };
+        params.check()?;
+        Ok(params)
+    }
+
+    /// Checks the costs against the limits accepted when reading a header
+    fn check(&self) -> Result<(), CliError> {
+        match self.memory_cost <= MAX_MEMORY_COST
+            && self.time_cost <= MAX_TIME_COST
+            && self.parallelism <= MAX_PARALLELISM
+        {
+            true => Ok(()),
+            false => Err(CliError::KdfCostTooHigh(MAX_MEMORY_COST, MAX_TIME_COST)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ENCODED_LEN);
+        bytes.extend_from_slice(&self.memory_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.time_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.parallelism.to_le_bytes());
+        bytes.extend_from_slice(&self.salt);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CliError> {
+        if bytes.len() != ENCODED_LEN {
+            return Err(CliError::InvalidHeader);
+        }
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let params = Argon2Params {
+            memory_cost: u32_at(0),
+            time_cost: u32_at(4),
+            parallelism: u32_at(8),
+            salt: bytes[12..].try_into().unwrap(),
+        };
+        params.check().map_err(|_| CliError::InvalidHeader)?;
+        Ok(params)
+    }
+
+    /// Derives the payload key from `password`
+    pub fn derive_key(&self, password: &str) -> Result<Key, CliError> {
+        let params = Params::new(
+            self.memory_cost,
+            self.time_cost,
+            self.parallelism,
+            Some(KEY_LEN),
+        )
+        .map_err(|_| CliError::KeyDerivationError)?;
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password.as_bytes(), &self.salt, &mut key)
+            .map_err(|_| CliError::KeyDerivationError)?;
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let params = Argon2Params::new(DEFAULT_MEMORY_COST, DEFAULT_TIME_COST).unwrap();
+        assert_eq!(
+            Argon2Params::from_bytes(&params.to_bytes()).unwrap(),
+            params
+        );
+    }
+
+    #[test]
+    fn rejects_excessive_costs() {
+        let mut params = Argon2Params::new(DEFAULT_MEMORY_COST, DEFAULT_TIME_COST).unwrap();
+        params.memory_cost = MAX_MEMORY_COST + 1;
+        assert!(Argon2Params::from_bytes(&params.to_bytes()).is_err());
+
+        params.memory_cost = DEFAULT_MEMORY_COST;
+        params.time_cost = MAX_TIME_COST + 1;
+        assert!(Argon2Params::from_bytes(&params.to_bytes()).is_err());
+
+        params.time_cost = DEFAULT_TIME_COST;
+        params.parallelism = MAX_PARALLELISM + 1;
+        assert!(Argon2Params::from_bytes(&params.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let bytes = Argon2Params::new(DEFAULT_MEMORY_COST, DEFAULT_TIME_COST)
+            .unwrap()
+            .to_bytes();
+        assert!(Argon2Params::from_bytes(&bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn new_rejects_costs_headers_cannot_carry() {
+        assert!(matches!(
+            Argon2Params::new(MAX_MEMORY_COST + 1, DEFAULT_TIME_COST),
+            Err(CliError::KdfCostTooHigh(..))
+        ));
+        assert!(matches!(
+            Argon2Params::new(DEFAULT_MEMORY_COST, MAX_TIME_COST + 1),
+            Err(CliError::KdfCostTooHigh(..))
+        ));
+        assert!(Argon2Params::new(MAX_MEMORY_COST, MAX_TIME_COST).is_ok());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{CliError, KEY_LEN};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::Key;
+use clap::ArgEnum;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::Write;
+
+/// Prefix of the typed key format
+const TYPED_PREFIX: &str = "crypt-key-v1";
+
+/// Length of a key id
+pub const KEY_ID_LEN: usize = 8;
+
+/// Length of the checksum of the typed key format
+const CHECKSUM_LEN: usize = 4;
+
+/// Encoding of a key file
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyEncoding {
+    /// The key bytes as is
+    Raw,
+    /// Hexadecimal text
+    Hex,
+    /// Standard base64 text
+    Base64,
+    /// `crypt-key-v1:<key id>:<base64 key>:<checksum>`
+    Typed,
+}
+
+/// Short identifier of a key that does not reveal anything about it
+pub fn key_id(key: &Key) -> [u8; KEY_ID_LEN] {
+    let mut id = [0u8; KEY_ID_LEN];
+    Hkdf::<Sha256>::new(None, key)
+        .expand(b"crypt key id", &mut id)
+        .expect("key id length is valid for HKDF-SHA256");
+    id
+}
+
+fn checksum(body: &str) -> String {
+    hex::encode(&Sha256::digest(body.as_bytes())[..CHECKSUM_LEN])
+}
+
+pub fn encode(key: &Key, encoding: KeyEncoding) -> Vec<u8> {
+    match encoding {
+        KeyEncoding::Raw => key.to_vec(),
+        KeyEncoding::Hex => format!("{}\n", hex::encode(key)).into_bytes(),
+        KeyEncoding::Base64 => format!("{}\n", BASE64.encode(key)).into_bytes(),
+        KeyEncoding::Typed => {
+            let typed = encode_typed(
+                TYPED_PREFIX,
+                &[hex::encode(key_id(key)), BASE64.encode(key)],
+            );
+            format!("{}\n", typed).into_bytes()
+        }
+    }
+}
+
+/// Encodes `fields` as `<prefix>:<field>:...:<checksum>`
+pub fn encode_typed(prefix: &str, fields: &[String]) -> String {
+    let body = [&[prefix.to_string()], fields].concat().join(":");
+    let sum = checksum(&body);
+    format!("{}:{}", body, sum)
+}
+
+/// Splits text in the typed format into its fields after checking its prefix and checksum
+pub fn decode_typed_fields<'a>(text: &'a str, prefix: &str) -> Result<Vec<&'a str>, CliError> {
+    let (body, sum) = text
+        .rsplit_once(':')
+        .ok_or_else(|| CliError::KeyDecodeError("missing checksum".to_string()))?;
+    if checksum(body) != sum.to_ascii_lowercase() {
+        return Err(CliError::KeyDecodeError("checksum mismatch".to_string()));
+    }
+
+    let mut fields = body.split(':');
+    if fields.next() != Some(prefix) {
+        return Err(CliError::KeyDecodeError(format!(
+            "expected a {} key",
+            prefix
+        )));
+    }
+    Ok(fields.collect())
+}
+
+pub fn encode_base64(data: &[u8]) -> String {
+    BASE64.encode(data)
+}
+
+/// Decodes a base64 field holding `KEY_LEN` bytes
+pub fn decode_base64_key(encoded: &str) -> Result<[u8; KEY_LEN], CliError> {
+    let key = BASE64
+        .decode(encoded)
+        .map_err(|e| CliError::KeyDecodeError(format!("invalid base64: {}", e)))?;
+    key.as_slice()
+        .try_into()
+        .map_err(|_| CliError::KeyLenError(key.len(), KEY_LEN))
+}
+
+/// Prefixes marking the encoding of a key given without an explicit encoding
+const ENCODING_PREFIXES: [(&str, KeyEncoding); 3] = [
+    ("hex:", KeyEncoding::Hex),
+    ("b64:", KeyEncoding::Base64),
+    ("base64:", KeyEncoding::Base64),
+];
+
+/// Decodes a key given on the command line or in a key file
+///
+/// Without an explicit `encoding`, typed keys and keys starting with one of
+/// [`ENCODING_PREFIXES`] are recognised and anything else is taken as raw bytes.
+pub fn decode(data: &[u8], encoding: Option<KeyEncoding>) -> Result<Key, CliError> {
+    let detected = ENCODING_PREFIXES
+        .iter()
+        .find(|(prefix, _)| data.starts_with(prefix.as_bytes()));
+    let (encoding, data) = match (encoding, detected) {
+        (Some(encoding), Some((prefix, detected))) if encoding == *detected => {
+            (encoding, &data[prefix.len()..])
+        }
+        (Some(encoding), _) => (encoding, data),
+        (None, Some((prefix, detected))) => (*detected, &data[prefix.len()..]),
+        (None, None) if data.starts_with(TYPED_PREFIX.as_bytes()) => (KeyEncoding::Typed, data),
+        (None, None) => (KeyEncoding::Raw, data),
+    };
+
+    let key = match encoding {
+        KeyEncoding::Raw => data.to_vec(),
+        KeyEncoding::Hex => hex::decode(text(data)?)
+            .map_err(|e| CliError::KeyDecodeError(format!("invalid hex: {}", e)))?,
+        KeyEncoding::Base64 => BASE64
+            .decode(text(data)?)
+            .map_err(|e| CliError::KeyDecodeError(format!("invalid base64: {}", e)))?,
+        KeyEncoding::Typed => return decode_typed(text(data)?),
+    };
+    match key.len() {
+        KEY_LEN => Ok(*Key::from_slice(&key)),
+        len => Err(CliError::KeyLenError(len, KEY_LEN)),
+    }
+}
+
+/// Interprets an encoded key as text, ignoring surrounding whitespace such as a trailing newline
+pub fn text(data: &[u8]) -> Result<&str, CliError> {
+    std::str::from_utf8(data)
+        .map(str::trim)
+        .map_err(|_| CliError::KeyDecodeError("key is not valid text".to_string()))
+}
+
+/// Decodes a key in the typed format, verifying its checksum and key id
+fn decode_typed(text: &str) -> Result<Key, CliError> {
+    let (id, encoded) = match decode_typed_fields(text, TYPED_PREFIX)?[..] {
+        [id, encoded] => (id, encoded),
+        _ => return Err(CliError::KeyDecodeError("malformed typed key".to_string())),
+    };
+    let key = Key::from(decode_base64_key(encoded)?);
+    if hex::encode(key_id(&key)) != id.to_ascii_lowercase() {
+        return Err(CliError::KeyDecodeError("key id mismatch".to_string()));
+    }
+    Ok(key)
+}
+
+/// Creates `file_name` holding `data`, readable only by the owner and never
+/// replacing an existing file
+pub fn write_new_secret(file_name: &str, data: &[u8]) -> Result<(), CliError> {
+    write_new(file_name, data, 0o600)
+}
+
+/// Creates `file_name` holding `data` with the unix permissions `mode`, never
+/// replacing an existing file
+pub fn write_new(file_name: &str, data: &[u8], mode: u32) -> Result<(), CliError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let mut file = options.open(file_name).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => CliError::OutputExists(file_name.to_string()),
+        _ => CliError::FileWriteError(file_name.to_string()),
+    })?;
+    file.write_all(data)
+        .and_then(|_| file.sync_all())
+        .map_err(|_| CliError::FileWriteError(file_name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::key;
+
+    const ENCODINGS: [KeyEncoding; 4] = [
+        KeyEncoding::Raw,
+        KeyEncoding::Hex,
+        KeyEncoding::Base64,
+        KeyEncoding::Typed,
+    ];
+
+    #[test]
+    fn round_trip() {
+        for encoding in ENCODINGS {
+            let encoded = encode(&key(1), encoding);
+            assert_eq!(decode(&encoded, Some(encoding)).unwrap(), key(1));
+        }
+    }
+
+    #[test]
+    fn detects_encoding() {
+        assert_eq!(
+            decode(&encode(&key(1), KeyEncoding::Raw), None).unwrap(),
+            key(1)
+        );
+        assert_eq!(
+            decode(&encode(&key(1), KeyEncoding::Typed), None).unwrap(),
+            key(1)
+        );
+        let hex = format!("hex:{}", hex::encode(key(1)));
+        assert_eq!(decode(hex.as_bytes(), None).unwrap(), key(1));
+        let base64 = format!("b64:{}", BASE64.encode(key(1)));
+        assert_eq!(decode(base64.as_bytes(), None).unwrap(), key(1));
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        assert!(matches!(
+            decode(&[0; KEY_LEN - 1], Some(KeyEncoding::Raw)),
+            Err(CliError::KeyLenError(31, KEY_LEN))
+        ));
+        assert!(decode(b"abcd", Some(KeyEncoding::Hex)).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupted_typed_key() {
+        let encoded = String::from_utf8(encode(&key(1), KeyEncoding::Typed)).unwrap();
+        let at = TYPED_PREFIX.len() + 1 + 2 * KEY_ID_LEN + 2;
+        let mut corrupted = encoded.into_bytes();
+        corrupted[at] = if corrupted[at] == b'A' { b'B' } else { b'A' };
+        assert!(decode(&corrupted, Some(KeyEncoding::Typed)).is_err());
+    }
+}
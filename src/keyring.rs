@@ -0,0 +1,130 @@
+use crate::cipher::CipherId;
+use crate::header::Kdf;
+use crate::kdf::{self, Argon2Params};
+use crate::keyfile::{self, KeyEncoding};
+use crate::{open_reader, read_preamble, write_replacing, CliError, Overwrite, Secret};
+use chacha20poly1305::Key;
+use std::path::Path;
+
+/// Location of the keyring below the data directory
+const KEYRING_PATH: &str = "crypt/keyring";
+
+/// Named keys stored in a file encrypted with a password, one `<name> <typed key>` per line
+#[derive(Default)]
+pub struct Keyring {
+    entries: Vec<(String, Key)>,
+}
+
+/// Path of the keyring, `$XDG_DATA_HOME/crypt/keyring` falling back to `~/.local/share`
+pub fn path() -> Result<String, CliError> {
+    let data_home = match std::env::var("XDG_DATA_HOME") {
+        Ok(dir) if Path::new(&dir).is_absolute() => dir,
+        _ => match std::env::var("HOME") {
+            Ok(home) if !home.is_empty() => format!("{}/.local/share", home),
+            _ => return Err(CliError::KeyringPathUnknown),
+        },
+    };
+    Ok(format!("{}/{}", data_home, KEYRING_PATH))
+}
+
+/// Checks that `name` can be stored on a keyring line
+fn check_name(name: &str) -> Result<(), CliError> {
+    match name.is_empty() || name.contains(char::is_whitespace) {
+        true => Err(CliError::InvalidKeyName(name.to_string())),
+        false => Ok(()),
+    }
+}
+
+impl Keyring {
+    /// Decrypts the keyring at `path` with `password`
+    pub fn load(path: &String, password: &str) -> Result<Keyring, CliError> {
+        let mut reader = open_reader(path)?;
+        let preamble = read_preamble(&mut reader, path)?;
+        let mut data = Vec::new();
+        crate::open(
+            &Secret::Password(password.to_string()),
+            preamble,
+            &mut reader,
+            &mut data,
+            path,
+            path,
+        )?;
+
+        let invalid = || CliError::InvalidKeyring(path.clone());
+        let text = String::from_utf8(data).map_err(|_| invalid())?;
+        let entries = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let (name, key) = line.split_once(' ').ok_or_else(invalid)?;
+                let key = keyfile::decode(key.as_bytes(), Some(KeyEncoding::Typed))
+                    .map_err(|_| invalid())?;
+                Ok((name.to_string(), key))
+            })
+            .collect::<Result<_, CliError>>()?;
+        Ok(Keyring { entries })
+    }
+
+    /// Encrypts the keyring with `password` into `path`, creating its directory when missing
+    pub fn save(&self, path: &String, password: &str) -> Result<(), CliError> {
+        if let Some(dir) = Path::new(path).parent() {
+            let mut builder = std::fs::DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::DirBuilderExt;
+                builder.mode(0o700);
+            }
+            builder
+                .create(dir)
+                .map_err(|_| CliError::FileWriteError(path.clone()))?;
+        }
+
+        let mut data = Vec::new();
+        for (name, key) in &self.entries {
+            data.extend_from_slice(name.as_bytes());
+            data.push(b' ');
+            data.extend(keyfile::encode(key, KeyEncoding::Typed));
+        }
+        let params = Argon2Params::new(kdf::DEFAULT_MEMORY_COST, kdf::DEFAULT_TIME_COST)?;
+        let key = params.derive_key(password)?;
+        let header = crate::new_header(&key, Kdf::Argon2id(params), CipherId::XChaCha20Poly1305);
+        write_replacing(path, Overwrite::Replace, 0o600, |writer| {
+            crate::seal(&key, header, &mut data.as_slice(), writer, path, path)
+        })
+    }
+
+    pub fn entries(&self) -> &[(String, Key)] {
+        &self.entries
+    }
+
+    pub fn keys(&self) -> Vec<Key> {
+        self.entries.iter().map(|(_, key)| *key).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<Key, CliError> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, key)| *key)
+            .ok_or_else(|| CliError::KeyNameNotFound(name.to_string()))
+    }
+
+    pub fn add(&mut self, name: String, key: Key) -> Result<(), CliError> {
+        check_name(&name)?;
+        if self.entries.iter().any(|(entry, _)| *entry == name) {
+            return Err(CliError::KeyNameExists(name));
+        }
+        self.entries.push((name, key));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Key, CliError> {
+        let index = self
+            .entries
+            .iter()
+            .position(|(entry, _)| entry == name)
+            .ok_or_else(|| CliError::KeyNameNotFound(name.to_string()))?;
+        Ok(self.entries.remove(index).1)
+    }
+}
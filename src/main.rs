@@ -1,11 +1,38 @@
+mod age;
+mod agent;
+mod cipher;
+mod header;
+mod kdf;
+mod keyfile;
+mod keyring;
+mod mnemonic;
+mod recipient;
+mod shamir;
+mod ssh;
+mod stream;
+#[cfg(test)]
+mod testutil;
+mod tree;
+
+use agent::Agent;
 use chacha20poly1305::{
     aead::{Aead, NewAead},
     ChaCha20Poly1305, Key, Nonce,
 };
-use clap::{Parser, Subcommand};
+use cipher::CipherId;
+use clap::{ArgEnum, Args, Parser, Subcommand};
+use header::{Header, Kdf};
+use kdf::Argon2Params;
+use keyfile::{KeyEncoding, KEY_ID_LEN};
+use keyring::Keyring;
 use rand::prelude::*;
-use std::io::{Read, Write};
+use recipient::{Identity, Recipient};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::path::Path;
+use stream::Stream;
 use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 /// Tool for encrypting and decrypting files utilizing ChaCha20
 #[derive(Parser)]
@@ -18,10 +45,134 @@ struct Cli {
 enum Command {
     /// Encrypt a file
     En {
-        /// File to decrypt
+        #[clap(flatten)]
+        path_args: PathArgs,
+
+        #[clap(flatten)]
+        key_args: KeyArgs,
+
+        /// X25519 public key (typed or `age1...`) to encrypt the file to, may be repeated and
+        /// combined with `--key-file` to make the file readable by each of them
+        #[clap(
+            long,
+            multiple_occurrences = true,
+            conflicts_with_all = &["password", "passphrase-fd"]
+        )]
+        recipient: Vec<String>,
+
+        /// File listing recipients one per line, such as `~/.ssh/authorized_keys`, may be repeated
+        #[clap(
+            long,
+            multiple_occurrences = true,
+            conflicts_with_all = &["password", "passphrase-fd"]
+        )]
+        recipient_file: Vec<String>,
+
+        #[clap(flatten)]
+        encrypt_args: EncryptArgs,
+    },
+
+    /// Decrypt a file
+    De {
+        #[clap(flatten)]
+        path_args: PathArgs,
+
+        #[clap(flatten)]
+        key_args: KeyArgs,
+
+        /// X25519 identity file (typed or age) to decrypt with, may be repeated and combined
+        /// with `--key-file`
+        #[clap(
+            long,
+            multiple_occurrences = true,
+            conflicts_with_all = &["password", "passphrase-fd"]
+        )]
+        identity: Vec<String>,
+
+        /// Format of the encrypted file, age files being detected as binary or armored
+        #[clap(long, arg_enum, default_value = "crypt")]
+        format: Format,
+    },
+
+    /// Generate a new random key
+    Keygen {
+        /// File to write the key to
         #[clap(long, short)]
         file: String,
 
+        /// Encoding of the key file
+        #[clap(
+            long,
+            short,
+            arg_enum,
+            default_value = "typed",
+            conflicts_with = "x25519"
+        )]
+        encoding: KeyEncoding,
+
+        /// Generate an X25519 identity instead, writing its public key to `<file>.pub`
+        #[clap(long)]
+        x25519: bool,
+    },
+
+    /// Manage keys
+    Key {
+        #[clap(subcommand)]
+        command: KeyCommand,
+    },
+
+    /// Manage the keyring of named keys in `$XDG_DATA_HOME/crypt/keyring`
+    Keyring {
+        #[clap(subcommand)]
+        command: KeyringCommand,
+    },
+
+    /// Hold the unlocked keyring and answer key requests of `en` and `de` on a Unix socket,
+    /// printing the shell command pointing them at it, as in `eval "$(crypt agent)"`
+    Agent {
+        /// Socket to listen on, created in a new directory under the temporary directory
+        /// when omitted
+        #[clap(long)]
+        socket: Option<String>,
+
+        /// Seconds without requests after which the agent exits
+        #[clap(long, default_value_t = agent::DEFAULT_TIMEOUT)]
+        timeout: u64,
+
+        /// Read the keyring password from the given file descriptor
+        #[clap(long)]
+        passphrase_fd: Option<i32>,
+
+        /// Stay in the foreground instead of forking into the background
+        #[clap(long)]
+        foreground: bool,
+    },
+
+    /// Replace the stanza of one key with a stanza of another in place, leaving the payload as is
+    Rekey {
+        /// Encrypted files to rekey
+        #[clap(required = true)]
+        files: Vec<String>,
+
+        /// Key file the files are currently encrypted for
+        #[clap(long)]
+        old_key_file: String,
+
+        /// Key file to encrypt the files for instead
+        #[clap(long)]
+        new_key_file: String,
+
+        /// Encoding of the keys, detected from a `hex:` or `b64:` prefix when omitted
+        #[clap(long, arg_enum)]
+        key_encoding: Option<KeyEncoding>,
+    },
+
+    /// Rewrite legacy headerless files into the current format in place
+    Migrate {
+        /// Encrypted files to migrate
+        #[clap(required = true)]
+        files: Vec<String>,
+
         /// Private key
         #[clap(long, short, group = "key_g")]
         key: Option<String>,
@@ -29,22 +180,406 @@ enum Command {
         /// Private key from a file
         #[clap(long, group = "key_g")]
         key_file: Option<String>,
+
+        /// Encoding of the key, detected from a `hex:` or `b64:` prefix when omitted
+        #[clap(long, arg_enum)]
+        key_encoding: Option<KeyEncoding>,
     },
+}
 
-    /// Decrypt a file
-    De {
-        /// File to decrypt
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Split a key into shares any `--threshold` of which recover it, written to
+    /// `<file>.share<n>`
+    Split {
+        /// Key file to split
+        file: String,
+
+        /// Number of shares needed to recover the key
+        #[clap(long, short)]
+        threshold: u8,
+
+        /// Number of shares to write
+        #[clap(long, short)]
+        shares: u8,
+
+        /// Encoding of the key, detected from a `hex:` or `b64:` prefix when omitted
+        #[clap(long, arg_enum)]
+        key_encoding: Option<KeyEncoding>,
+    },
+
+    /// Recover a key from its shares into a key file
+    Combine {
+        /// Share files written by `key split`
+        #[clap(required = true)]
+        shares: Vec<String>,
+
+        /// File to write the key to
         #[clap(long, short)]
         file: String,
 
-        /// Private key
-        #[clap(long, short, group = "key_g")]
-        key: Option<String>,
+        /// Encoding of the key file
+        #[clap(long, short, arg_enum, default_value = "typed")]
+        encoding: KeyEncoding,
+    },
 
-        /// Private key from a file
-        #[clap(long, group = "key_g")]
+    /// Print a key for a backup, such as a word list to keep on paper
+    Export {
+        /// Key file to export
+        file: String,
+
+        /// Print the key as a checksummed list of words
+        #[clap(long)]
+        mnemonic: bool,
+
+        /// Encoding to print the key in
+        #[clap(
+            long,
+            short,
+            arg_enum,
+            default_value = "typed",
+            conflicts_with = "mnemonic"
+        )]
+        encoding: KeyEncoding,
+
+        /// Encoding of the key, detected from a `hex:` or `b64:` prefix when omitted
+        #[clap(long, arg_enum)]
+        key_encoding: Option<KeyEncoding>,
+    },
+
+    /// Restore a key read from standard input into a key file
+    Import {
+        /// File to write the key to
+        #[clap(long, short)]
+        file: String,
+
+        /// Read the key as a list of words, each of which may be shortened to a unique prefix
+        #[clap(long)]
+        mnemonic: bool,
+
+        /// Encoding of the key file
+        #[clap(long, short, arg_enum, default_value = "typed")]
+        encoding: KeyEncoding,
+
+        /// Encoding of the key read, detected from a `hex:` or `b64:` prefix when omitted
+        #[clap(long, arg_enum, conflicts_with = "mnemonic")]
+        key_encoding: Option<KeyEncoding>,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyringCommand {
+    /// Add a key to the keyring, generating a new one unless `--key-file` is given
+    Add {
+        /// Name to select the key with
+        name: String,
+
+        /// Key file to add
+        #[clap(long)]
         key_file: Option<String>,
+
+        /// Encoding of the key, detected from a `hex:` or `b64:` prefix when omitted
+        #[clap(long, arg_enum)]
+        key_encoding: Option<KeyEncoding>,
+
+        /// Read the keyring password from the given file descriptor
+        #[clap(long)]
+        passphrase_fd: Option<i32>,
     },
+
+    /// List the names and key ids of the keys in the keyring
+    List {
+        /// Read the keyring password from the given file descriptor
+        #[clap(long)]
+        passphrase_fd: Option<i32>,
+    },
+
+    /// Remove a key from the keyring
+    Remove {
+        /// Name of the key
+        name: String,
+
+        /// Read the keyring password from the given file descriptor
+        #[clap(long)]
+        passphrase_fd: Option<i32>,
+    },
+
+    /// Write a key of the keyring to a key file
+    Export {
+        /// Name of the key
+        name: String,
+
+        /// File to write the key to
+        #[clap(long, short)]
+        file: String,
+
+        /// Encoding of the key file
+        #[clap(long, short, arg_enum, default_value = "typed")]
+        encoding: KeyEncoding,
+
+        /// Read the keyring password from the given file descriptor
+        #[clap(long)]
+        passphrase_fd: Option<i32>,
+    },
+}
+
+#[derive(Args)]
+struct EncryptArgs {
+    /// Format of the encrypted file
+    #[clap(long, arg_enum, default_value = "crypt")]
+    format: Format,
+
+    /// Armor the encrypted file as text (age format only)
+    #[clap(long)]
+    armor: bool,
+
+    /// Cipher used to encrypt the file, xchacha20poly1305 by default (crypt format only)
+    #[clap(long, arg_enum)]
+    cipher: Option<CipherId>,
+
+    /// Argon2id memory cost in KiB when deriving the key from a password, 65536 by default
+    /// (crypt format only)
+    #[clap(long)]
+    memory_cost: Option<u32>,
+
+    /// Argon2id iterations when deriving the key from a password, 3 by default (crypt format
+    /// only)
+    #[clap(long)]
+    time_cost: Option<u32>,
+}
+
+/// Format of an encrypted file
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// `.crypt` files
+    Crypt,
+    /// age v1 files, see <https://age-encryption.org/v1>
+    Age,
+}
+
+impl Format {
+    /// Extension appended to the name of an encrypted file
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Crypt => "crypt",
+            Format::Age => "age",
+        }
+    }
+}
+
+#[derive(Args)]
+struct PathArgs {
+    /// Unencrypted file, read by `en` and written by `de` next to `<file><suffix>`, which `de`
+    /// may also be given. `-` reads standard input and writes standard output.
+    #[clap(
+        long,
+        short,
+        conflicts_with = "input",
+        required_unless_present = "input"
+    )]
+    file: Option<String>,
+
+    /// File to read, `-` for standard input
+    #[clap(long, short)]
+    input: Option<String>,
+
+    /// File to write, `-` for standard output. Defaults to the input with the suffix added
+    /// when encrypting and removed when decrypting.
+    #[clap(long, short)]
+    output: Option<String>,
+
+    /// Write standard output, like `--output -`
+    #[clap(long, conflicts_with = "output")]
+    stdout: bool,
+
+    /// Suffix of encrypted files, `.crypt` or `.age` depending on `--format` by default
+    #[clap(long)]
+    suffix: Option<String>,
+
+    /// Replace the output file if it already exists
+    #[clap(long, conflicts_with = "backup")]
+    force: bool,
+
+    /// Replace the output file if it already exists, keeping it as `<output>.bak`
+    #[clap(long)]
+    backup: bool,
+
+    /// Encrypt or decrypt every file below the input directory, into the same tree below the
+    /// output directory or next to each file without one
+    #[clap(long, short, conflicts_with = "stdout")]
+    recursive: bool,
+}
+
+/// Files read and written by `en` and `de`, `None` standing for standard input or output
+struct Paths {
+    input: Option<String>,
+    output: Option<String>,
+    overwrite: Overwrite,
+}
+
+/// What writing a file does when it already exists
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Overwrite {
+    /// Fail with [`CliError::OutputExists`]
+    Refuse,
+    Replace,
+    /// Replace it after renaming it to `<file>.bak`
+    Backup,
+}
+
+impl PathArgs {
+    /// Suffix of encrypted files, with its leading dot
+    fn suffix(&self, format: Format) -> String {
+        match &self.suffix {
+            Some(suffix) => format!(".{}", suffix.trim_start_matches('.')),
+            None => format!(".{}", format.extension()),
+        }
+    }
+
+    fn overwrite(&self) -> Overwrite {
+        match (self.force, self.backup) {
+            (true, _) => Overwrite::Replace,
+            (_, true) => Overwrite::Backup,
+            _ => Overwrite::Refuse,
+        }
+    }
+
+    /// Input and output directories of `--recursive`, the output being absent when writing in
+    /// place
+    fn dirs(&self) -> Result<(&Path, Option<&Path>), CliError> {
+        let input = match self.file.as_ref().or(self.input.as_ref()) {
+            Some(input) => input,
+            None => unreachable!("clap requires exactly one of --file and --input"),
+        };
+        if input == STDIO_NAME || self.output.as_deref() == Some(STDIO_NAME) {
+            return Err(CliError::RecursiveStdio);
+        }
+        match Path::new(input).is_dir() {
+            true => Ok((Path::new(input), self.output.as_ref().map(Path::new))),
+            false => Err(CliError::NotADirectory(input.clone())),
+        }
+    }
+
+    /// Resolves the input and output of encrypting, or of decrypting when `encrypting` is unset,
+    /// refusing an output that is the input itself
+    fn paths(&self, format: Format, encrypting: bool) -> Result<Paths, CliError> {
+        let suffix = self.suffix(format);
+        let input = match (&self.file, &self.input) {
+            (Some(file), None) if !encrypting && file != STDIO_NAME && !file.ends_with(&suffix) => {
+                format!("{}{}", file, suffix)
+            }
+            (Some(input), None) | (None, Some(input)) => input.clone(),
+            _ => unreachable!("clap requires exactly one of --file and --input"),
+        };
+        let input = (input != STDIO_NAME).then_some(input);
+        let output = match (&self.output, self.stdout, &input) {
+            (Some(output), ..) => output.clone(),
+            (None, true, _) | (None, false, None) => STDIO_NAME.to_string(),
+            (None, false, Some(input)) if encrypting => format!("{}{}", input, suffix),
+            (None, false, Some(input)) => match input.strip_suffix(&suffix) {
+                Some(output) if !output.is_empty() => output.to_string(),
+                _ => return Err(CliError::OutputUnknown(input.clone(), suffix)),
+            },
+        };
+        let output = (output != STDIO_NAME).then_some(output);
+        Paths::new(input, output, self.overwrite())
+    }
+}
+
+impl Paths {
+    /// Refuses an output that is the input itself, or that exists unless `overwrite` allows it
+    fn new(
+        input: Option<String>,
+        output: Option<String>,
+        overwrite: Overwrite,
+    ) -> Result<Paths, CliError> {
+        if let (Some(input), Some(output)) = (&input, &output) {
+            let same = std::fs::canonicalize(input)
+                .and_then(|input| Ok(input == std::fs::canonicalize(output)?))
+                .unwrap_or(false);
+            if same {
+                return Err(CliError::OutputIsInput(output.clone()));
+            }
+        }
+
+        // Checked again before the output is put in place, this fails before asking for a
+        // password or doing any work
+        match &output {
+            Some(output) if overwrite == Overwrite::Refuse && Path::new(output).exists() => {
+                Err(CliError::OutputExists(output.clone()))
+            }
+            _ => Ok(Paths {
+                input,
+                output,
+                overwrite,
+            }),
+        }
+    }
+
+    /// Name of the input in messages
+    fn input_name(&self) -> String {
+        self.input.clone().unwrap_or_else(|| STDIN_NAME.to_string())
+    }
+
+    /// Name of the output in messages
+    fn output_name(&self) -> String {
+        self.output
+            .clone()
+            .unwrap_or_else(|| STDOUT_NAME.to_string())
+    }
+}
+
+#[derive(Args)]
+struct KeyArgs {
+    /// Private key
+    #[clap(long, short, group = "key_g")]
+    key: Option<String>,
+
+    /// Private key from a file, may be repeated and combined with `--key` and `--key-name` to
+    /// encrypt for each key or to decrypt with whichever key matches the file
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        conflicts_with_all = &["password", "passphrase-fd"]
+    )]
+    key_file: Vec<String>,
+
+    /// Name of a key in the keyring, may be repeated like `--key-file`. The key is used
+    /// through the agent when `CRYPT_AGENT_SOCK` is set.
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        conflicts_with_all = &["password", "passphrase-fd"]
+    )]
+    key_name: Vec<String>,
+
+    /// Encoding of the key, detected from a `hex:` or `b64:` prefix when omitted
+    #[clap(long, arg_enum)]
+    key_encoding: Option<KeyEncoding>,
+
+    /// Derive the key from a password, prompting for it when no value is given
+    #[clap(long, group = "key_g", min_values = 0, max_values = 1)]
+    password: Option<Option<String>>,
+
+    /// Read the password from the given file descriptor
+    #[clap(long, group = "key_g")]
+    passphrase_fd: Option<i32>,
+
+    /// Read the keyring password from the given file descriptor
+    #[clap(long)]
+    keyring_passphrase_fd: Option<i32>,
+}
+
+impl KeyArgs {
+    /// Whether any key, rather than a password, was given
+    fn has_keys(&self) -> bool {
+        self.key.is_some() || !self.key_file.is_empty() || !self.key_name.is_empty()
+    }
+
+    /// Whether any key or password was given
+    fn has_secret(&self) -> bool {
+        self.has_keys() || self.password.is_some() || self.passphrase_fd.is_some()
+    }
 }
 
 #[derive(Debug, Error)]
@@ -58,38 +593,394 @@ enum CliError {
     #[error("Key length is invalid (Actual: {0} Expected: {1})")]
     KeyLenError(usize, usize),
 
+    #[error("Could not decode key: {0}")]
+    KeyDecodeError(String),
+
+    #[error("Unsupported key type {0}")]
+    UnsupportedKeyType(String),
+
+    #[error("No supported recipient in {0}")]
+    NoRecipients(String),
+
+    #[error("File {0} already exists")]
+    OutputExists(String),
+
     #[error("Could not encrypt data")]
     EncryptionError,
 
     #[error("Could not decrypt data")]
     DecryptionError,
+
+    #[error("File header is missing or malformed")]
+    InvalidHeader,
+
+    #[error("Armored file is malformed")]
+    InvalidArmor,
+
+    #[error("{0} cannot be used with this format")]
+    UnsupportedByFormat(String),
+
+    #[error("File format version is not supported (Actual: {0} Supported: {1})")]
+    UnsupportedVersion(u8, u8),
+
+    #[error("Cipher is not supported (Id: {0})")]
+    UnsupportedCipher(u8),
+
+    #[error("Key derivation function is not supported (Id: {0})")]
+    UnsupportedKdf(u8),
+
+    #[error("Header flags are not supported (Flags: {0:#06x})")]
+    UnsupportedFlags(u16),
+
+    #[error("Encrypted data is truncated")]
+    Truncated,
+
+    #[error("Chunk {0} was found at position {1}")]
+    ChunkOutOfOrder(u32, u32),
+
+    #[error("Unexpected data after the final chunk")]
+    TrailingData,
+
+    #[error("No key was provided")]
+    KeyMissing,
+
+    #[error("File was encrypted with a password")]
+    PasswordRequired,
+
+    #[error("File was encrypted with a key")]
+    KeyRequired,
+
+    #[error("File was encrypted to recipients, a key or identity is required")]
+    IdentityRequired,
+
+    #[error("None of the supplied keys or identities can decrypt this file")]
+    NoMatchingIdentity,
+
+    #[error("File header failed authentication")]
+    HeaderAuthError,
+
+    #[error("Could not read password")]
+    PasswordReadError,
+
+    #[error("Passwords do not match")]
+    PasswordMismatch,
+
+    #[error("Password must not be empty")]
+    PasswordEmpty,
+
+    #[error("Password is incorrect")]
+    WrongPassword,
+
+    #[error("File was encrypted with key(s) {0}, supplied key(s): {1}")]
+    KeyMismatch(String, String),
+
+    #[error("Could not derive key from password")]
+    KeyDerivationError,
+
+    #[error("Argon2id costs must not exceed those accepted when decrypting (Memory cost: {0} KiB Time cost: {1})")]
+    KdfCostTooHigh(u32, u32),
+
+    #[error("Could not migrate {0} file(s)")]
+    MigrationError(usize),
+
+    #[error("File does not wrap its key in stanzas and must be re-encrypted")]
+    NotEnveloped,
+
+    #[error("File has no stanza for the old key")]
+    OldKeyNotFound,
+
+    #[error("Could not rekey {0} file(s)")]
+    RekeyError(usize),
+
+    #[error("Threshold must be between 1 and the number of shares (Threshold: {0} Shares: {1})")]
+    InvalidThreshold(u8, u8),
+
+    #[error("Not enough shares to recover the key (Actual: {0} Required: {1})")]
+    NotEnoughShares(usize, u8),
+
+    #[error("Shares do not belong to the same key")]
+    ShareMismatch,
+
+    #[error("Could not locate the keyring, set XDG_DATA_HOME or HOME")]
+    KeyringPathUnknown,
+
+    #[error("Keyring {0} does not exist")]
+    KeyringNotFound(String),
+
+    #[error("Keyring {0} is malformed")]
+    InvalidKeyring(String),
+
+    #[error("Key name {0:?} must not be empty or contain whitespace")]
+    InvalidKeyName(String),
+
+    #[error("Key {0} is not in the keyring")]
+    KeyNameNotFound(String),
+
+    #[error("Key {0} is already in the keyring")]
+    KeyNameExists(String),
+
+    #[error("Could not listen on agent socket {0}")]
+    AgentSocketError(String),
+
+    #[error("Could not reach the agent at {0}")]
+    AgentUnavailable(String),
+
+    #[error("Agent refused the request")]
+    AgentDenied,
+
+    #[error("Could not start the agent in the background")]
+    AgentDetachError,
+
+    #[error("Output {0} is the input file")]
+    OutputIsInput(String),
+
+    #[error("Cannot derive the output name from {0} by removing {1}, use --output")]
+    OutputUnknown(String, String),
+
+    #[error("{0} is not a directory")]
+    NotADirectory(String),
+
+    #[error("--recursive cannot read standard input or write standard output")]
+    RecursiveStdio,
+
+    #[error("Could not process {0} file(s)")]
+    TreeError(usize),
 }
 
 const KEY_LEN: usize = 32;
-const NONCE_LEN: usize = 12;
+const LEGACY_NONCE_LEN: usize = 12;
 
-fn get_key(raw_key: Option<String>, key_file: Option<String>) -> Result<Key, CliError> {
-    let key = if let Some(raw_key) = raw_key {
-        raw_key.chars().map(|c| c as u8).collect()
-    } else {
-        read_bytes(&key_file.unwrap())?
+/// Names of standard input and output in messages
+const STDIN_NAME: &str = "standard input";
+const STDOUT_NAME: &str = "standard output";
+/// Name standing for standard input or output in place of a file name
+const STDIO_NAME: &str = "-";
+
+/// Secret supplied on the command line to encrypt or decrypt with
+enum Secret {
+    /// Candidate keys, the one matching the key id of a file being picked to decrypt it
+    Keys(Vec<Key>),
+    Password(String),
+    /// Keys and X25519 identities unwrapping the payload key from a stanza, along with the
+    /// agent holding the keys named alongside them
+    Identities {
+        keys: Vec<Key>,
+        identities: Vec<Identity>,
+        agent: Option<Agent>,
+    },
+    /// Keys along with the names of keys held by the agent
+    Agent {
+        keys: Vec<Key>,
+        names: Vec<String>,
+        agent: Agent,
+    },
+}
+
+/// Reads the secret selected by `key_args`, prompting for a password on the terminal when
+/// no key source is given. `confirm` asks for the password twice, as when encrypting.
+fn get_secret(key_args: KeyArgs, confirm: bool) -> Result<Secret, CliError> {
+    if let Some(fd) = key_args.passphrase_fd {
+        return Ok(Secret::Password(read_password_fd(fd)?));
+    }
+    match key_args.password {
+        Some(Some(password)) => Ok(Secret::Password(password)),
+        Some(None) => Ok(Secret::Password(prompt_password("Password", confirm)?)),
+        None if !key_args.has_keys() => Ok(Secret::Password(prompt_password("Password", confirm)?)),
+        None => {
+            let mut keys = Vec::new();
+            if let Some(raw_key) = key_args.key {
+                keys.push(get_key(Some(raw_key), None, key_args.key_encoding)?);
+            }
+            for key_file in key_args.key_file {
+                keys.push(get_key(None, Some(key_file), key_args.key_encoding)?);
+            }
+            if let (false, Some(agent)) = (key_args.key_name.is_empty(), Agent::from_env()) {
+                return Ok(Secret::Agent {
+                    keys,
+                    names: key_args.key_name,
+                    agent,
+                });
+            }
+            if !key_args.key_name.is_empty() {
+                let (keyring, _) = unlock_keyring(key_args.keyring_passphrase_fd, false)?;
+                for name in &key_args.key_name {
+                    keys.push(keyring.get(name)?);
+                }
+            }
+            Ok(Secret::Keys(keys))
+        }
+    }
+}
+
+/// Prompts for the password called `name` on the controlling terminal with echo disabled
+fn prompt_password(name: &str, confirm: bool) -> Result<String, CliError> {
+    let password = rpassword::prompt_password(format!("{}: ", name))
+        .map_err(|_| CliError::PasswordReadError)?;
+    if !confirm {
+        return Ok(password);
+    }
+
+    if password.is_empty() {
+        return Err(CliError::PasswordEmpty);
+    }
+    let confirmation = rpassword::prompt_password(format!("Confirm {}: ", name.to_lowercase()))
+        .map_err(|_| CliError::PasswordReadError)?;
+    match password == confirmation {
+        true => Ok(password),
+        false => Err(CliError::PasswordMismatch),
+    }
+}
+
+/// Reads a password from the first line of the file descriptor `fd`, leaving it open
+#[cfg(unix)]
+fn read_password_fd(fd: i32) -> Result<String, CliError> {
+    use std::os::unix::io::FromRawFd;
+
+    // Read byte by byte so nothing past the first line is consumed from the descriptor
+    let mut file = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        match file.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => return Err(CliError::PasswordReadError),
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| CliError::PasswordReadError)
+}
+
+#[cfg(not(unix))]
+fn read_password_fd(_fd: i32) -> Result<String, CliError> {
+    Err(CliError::PasswordReadError)
+}
+
+/// Decrypts the keyring, returning it along with its password. `create` starts an empty
+/// keyring, asking for a new password, when none exists yet.
+fn unlock_keyring(passphrase_fd: Option<i32>, create: bool) -> Result<(Keyring, String), CliError> {
+    let path = keyring::path()?;
+    let exists = std::path::Path::new(&path).exists();
+    if !exists && !create {
+        return Err(CliError::KeyringNotFound(path));
+    }
+    let password = match passphrase_fd {
+        Some(fd) => read_password_fd(fd)?,
+        None => prompt_password("Keyring password", !exists)?,
     };
+    match exists {
+        true => Ok((Keyring::load(&path, &password)?, password)),
+        false if password.is_empty() => Err(CliError::PasswordEmpty),
+        false => Ok((Keyring::default(), password)),
+    }
+}
+
+/// Error listing the ids of the keys a file was encrypted with next to those of the supplied keys
+fn key_mismatch(key_ids: &[[u8; KEY_ID_LEN]], keys: &[Key]) -> CliError {
+    let key_ids: Vec<String> = key_ids.iter().map(hex::encode).collect();
+    let supplied: Vec<String> = keys
+        .iter()
+        .map(|key| hex::encode(keyfile::key_id(key)))
+        .collect();
+    CliError::KeyMismatch(key_ids.join(", "), supplied.join(", "))
+}
 
-    match key.len() {
-        KEY_LEN => Ok(*Key::from_slice(&key)),
-        len => Err(CliError::KeyLenError(len, KEY_LEN)),
+/// Resolves the payload key of a file from its header, checking it against the key id
+fn resolve_key(secret: &Secret, header: &Header) -> Result<Key, CliError> {
+    if header.has_recipients() {
+        let (keys, identities, agent) = match secret {
+            Secret::Keys(keys) => (keys.as_slice(), [].as_slice(), None),
+            Secret::Identities {
+                keys,
+                identities,
+                agent,
+            } => (keys.as_slice(), identities.as_slice(), agent.as_ref()),
+            Secret::Agent { keys, agent, .. } => (keys.as_slice(), [].as_slice(), Some(agent)),
+            Secret::Password(_) => return Err(CliError::IdentityRequired),
+        };
+        let mut key = header.stanzas.iter().find_map(|stanza| {
+            keys.iter()
+                .find_map(|key| recipient::unwrap_key(stanza, key))
+                .or_else(|| {
+                    identities
+                        .iter()
+                        .find_map(|identity| identity.unwrap(stanza))
+                })
+        });
+        if let (None, Some(agent)) = (key, agent) {
+            for stanza in &header.stanzas {
+                key = agent.unwrap(stanza)?;
+                if key.is_some() {
+                    break;
+                }
+            }
+        }
+        let key = key.ok_or_else(|| {
+            let key_ids: Vec<_> = header
+                .stanzas
+                .iter()
+                .filter_map(recipient::stanza_key_id)
+                .collect();
+            match key_ids.is_empty() || keys.is_empty() {
+                true => CliError::NoMatchingIdentity,
+                false => key_mismatch(&key_ids, keys),
+            }
+        })?;
+        recipient::verify_header_mac(&key, header)?;
+        return match keyfile::key_id(&key) == header.key_id {
+            true => Ok(key),
+            false => Err(CliError::HeaderAuthError),
+        };
     }
+
+    match (secret, &header.kdf) {
+        (Secret::Keys(keys), Kdf::None) => keys
+            .iter()
+            .find(|key| keyfile::key_id(key) == header.key_id)
+            .copied()
+            .ok_or_else(|| key_mismatch(&[header.key_id], keys)),
+        (Secret::Password(password), Kdf::Argon2id(params)) => {
+            let key = params.derive_key(password)?;
+            match keyfile::key_id(&key) == header.key_id {
+                true => Ok(key),
+                false => Err(CliError::WrongPassword),
+            }
+        }
+        (Secret::Identities { keys, .. }, Kdf::None) if !keys.is_empty() => {
+            resolve_key(&Secret::Keys(keys.clone()), header)
+        }
+        (Secret::Agent { keys, .. }, Kdf::None) => resolve_key(&Secret::Keys(keys.clone()), header),
+        (Secret::Keys(_) | Secret::Identities { .. } | Secret::Agent { .. }, Kdf::Argon2id(_)) => {
+            Err(CliError::PasswordRequired)
+        }
+        (Secret::Password(_) | Secret::Identities { .. }, Kdf::None) => Err(CliError::KeyRequired),
+    }
+}
+
+fn get_key(
+    raw_key: Option<String>,
+    key_file: Option<String>,
+    encoding: Option<KeyEncoding>,
+) -> Result<Key, CliError> {
+    let data = match (raw_key, key_file) {
+        (Some(raw_key), _) => raw_key.into_bytes(),
+        (None, Some(key_file)) => read_bytes(&key_file)?,
+        (None, None) => return Err(CliError::KeyMissing),
+    };
+    keyfile::decode(&data, encoding)
 }
 
-fn new_rand_nonce() -> Vec<u8> {
+fn new_rand_nonce(len: usize) -> Vec<u8> {
     let mut rng = rand::thread_rng();
-    (0..NONCE_LEN).map(|_| rng.gen()).collect()
+    (0..len).map(|_| rng.gen()).collect()
 }
 
 fn read_bytes(file_name: &String) -> Result<Vec<u8>, CliError> {
-    let file =
-        std::fs::File::open(&file_name).map_err(|_| CliError::FileReadError(file_name.clone()))?;
-    let mut reader = std::io::BufReader::new(file);
+    let mut reader = open_reader(file_name)?;
     let mut data = Vec::new();
     reader
         .read_to_end(&mut data)
@@ -97,43 +988,993 @@ fn read_bytes(file_name: &String) -> Result<Vec<u8>, CliError> {
     Ok(data)
 }
 
-fn write_bytes(file_name: &String, text: Vec<u8>) -> Result<(), CliError> {
-    let mut crypt_file = std::fs::File::create(&file_name)
-        .map_err(|_| CliError::FileWriteError(file_name.clone()))?;
-    crypt_file
-        .write(text.as_ref())
-        .map_err(|_| CliError::FileWriteError(file_name.clone()))?;
-    Ok(())
+fn open_reader(file_name: &String) -> Result<BufReader<File>, CliError> {
+    let file = File::open(file_name).map_err(|_| CliError::FileReadError(file_name.clone()))?;
+    Ok(BufReader::new(file))
+}
+
+/// Opens `file_name` for reading, or standard input for `None`
+fn open_input(file_name: Option<&String>) -> Result<Box<dyn BufRead>, CliError> {
+    match file_name {
+        Some(file_name) => Ok(Box::new(open_reader(file_name)?)),
+        None => Ok(Box::new(std::io::stdin().lock())),
+    }
+}
+
+/// Writes the output of `paths` with `write` as [`write_replacing`] does, or standard output
+fn write_output<F>(paths: &Paths, write: F) -> Result<(), CliError>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), CliError>,
+{
+    if let Some(file_name) = &paths.output {
+        return write_replacing(file_name, paths.overwrite, 0o666, |writer| write(writer));
+    }
+    let mut writer = BufWriter::new(std::io::stdout().lock());
+    write(&mut writer)?;
+    writer
+        .flush()
+        .map_err(|_| CliError::FileWriteError(STDOUT_NAME.to_string()))
+}
+
+/// Writes `file_name` all or nothing: `write` fills a new temp file in the same directory,
+/// which is synced to disk and only then moved to `file_name` as `overwrite` allows, so a
+/// crash or a full disk never leaves a truncated file or clobbers an existing one. The new
+/// file gets the unix permissions `mode`, narrowed to those of any file it replaces
+fn write_replacing<F>(
+    file_name: &String,
+    overwrite: Overwrite,
+    mode: u32,
+    write: F,
+) -> Result<(), CliError>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), CliError>,
+{
+    let write_error = || CliError::FileWriteError(file_name.clone());
+    let path = Path::new(file_name);
+    let name = path.file_name().ok_or_else(write_error)?;
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        hex::encode(rand::thread_rng().gen::<[u8; 6]>())
+    ));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let file = options.open(&tmp_path).map_err(|_| write_error())?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        let mut permissions = metadata.permissions();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            permissions.set_mode(permissions.mode() & mode);
+        }
+        let _ = file.set_permissions(permissions);
+    }
+
+    let mut writer = BufWriter::new(file);
+    let result = write(&mut writer)
+        .and_then(|_| writer.into_inner().map_err(|_| write_error()))
+        .and_then(|file| file.sync_all().map_err(|_| write_error()))
+        .and_then(|_| match overwrite {
+            // Unlike a rename, linking fails if the file has appeared since it was checked for
+            Overwrite::Refuse => std::fs::hard_link(&tmp_path, path)
+                .map(|_| {
+                    let _ = std::fs::remove_file(&tmp_path);
+                })
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::AlreadyExists => CliError::OutputExists(file_name.clone()),
+                    _ => write_error(),
+                }),
+            // The original stays in place until the rename replaces it
+            Overwrite::Backup if path.exists() => {
+                let backup = format!("{}.bak", file_name);
+                let _ = std::fs::remove_file(&backup);
+                std::fs::hard_link(path, &backup)
+                    .and_then(|_| std::fs::rename(&tmp_path, path))
+                    .map_err(|_| write_error())
+            }
+            _ => std::fs::rename(&tmp_path, path).map_err(|_| write_error()),
+        });
+    match result {
+        Ok(()) => sync_parent(path),
+        Err(_) => {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+    }
+    result
+}
+
+/// Syncs the directory holding `path` so that a rename into it survives a crash
+#[cfg(unix)]
+fn sync_parent(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    // Some file systems cannot sync directories, in which case the rename is as durable as
+    // they make it
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) {}
+
+/// Leading bytes of an encrypted file
+enum Preamble {
+    /// Complete contents of a legacy headerless file
+    Legacy(Vec<u8>),
+    Header(Header),
+}
+
+fn read_preamble<R: Read>(reader: &mut R, file_name: &str) -> Result<Preamble, CliError> {
+    let read_error = |_| CliError::FileReadError(file_name.to_string());
+    let mut data = vec![0u8; header::FIXED_LEN];
+    let len = stream::read_full(reader, &mut data).map_err(read_error)?;
+    data.truncate(len);
+    if header::is_legacy(&data) {
+        reader.read_to_end(&mut data).map_err(read_error)?;
+        return Ok(Preamble::Legacy(data));
+    }
+
+    loop {
+        let missing = Header::missing_len(&data)?;
+        if missing == 0 {
+            break;
+        }
+        let start = data.len();
+        data.resize(start + missing, 0);
+        if stream::read_full(reader, &mut data[start..]).map_err(read_error)? < missing {
+            return Err(CliError::InvalidHeader);
+        }
+    }
+    let (header, _) = Header::parse(&data)?;
+    Ok(Preamble::Header(header))
+}
+
+/// Creates the header of a file whose payload is encrypted under `key`
+///
+/// `key` must not encrypt any other file: with 12 byte nonces the random nonce prefix is only
+/// 7 bytes, so prefixes of files sharing a key would be expected to collide after about 2^28
+/// files. Callers therefore pass a random payload key or one derived with a fresh salt.
+fn new_header(key: &Key, kdf: Kdf, cipher: CipherId) -> Header {
+    Header::new(
+        cipher,
+        kdf,
+        keyfile::key_id(key),
+        new_rand_nonce(cipher.nonce_prefix_len()),
+    )
+}
+
+/// Creates the header of a file whose payload key is random and wrapped once for each of
+/// `keys` and `recipients`, returning the payload key along with it
+fn envelope_header(
+    keys: &[Key],
+    recipients: &[Recipient],
+    cipher: CipherId,
+) -> Result<(Key, Header), CliError> {
+    let key: Key = rand::thread_rng().gen::<[u8; KEY_LEN]>().into();
+    let mut stanzas = Vec::new();
+    for wrapping_key in keys {
+        stanzas.push(recipient::wrap_key(&key, wrapping_key)?);
+    }
+    for recipient in recipients {
+        stanzas.push(recipient.wrap(&key)?);
+    }
+    let mut header = new_header(&key, Kdf::None, cipher);
+    header.set_stanzas(stanzas);
+    Ok((key, header))
+}
+
+/// Encrypts `reader` under `key` into `writer` as a `.crypt` file, starting with `header`
+fn seal<R: Read, W: Write>(
+    key: &Key,
+    mut header: Header,
+    reader: &mut R,
+    writer: &mut W,
+    input_name: &str,
+    output_name: &str,
+) -> Result<(), CliError> {
+    if header.has_recipients() {
+        header.mac = recipient::header_mac(key, &header);
+    }
+    writer
+        .write_all(&header.to_bytes())
+        .map_err(|_| CliError::FileWriteError(output_name.to_string()))?;
+    Stream::new(
+        &*header.cipher.new_cipher(key),
+        &header.nonce_prefix,
+        header.chunk_len,
+        &header.aad(),
+    )
+    .encrypt(reader, writer, input_name, output_name)
+}
+
+/// Decrypts the contents of a legacy headerless file, laid out as `nonce || ciphertext`,
+/// with the first of `keys` that authenticates it
+fn open_legacy(keys: &[Key], data: &[u8]) -> Result<Vec<u8>, CliError> {
+    if data.len() < LEGACY_NONCE_LEN {
+        return Err(CliError::DecryptionError);
+    }
+    let (nonce, ciphertext) = data.split_at(LEGACY_NONCE_LEN);
+    keys.iter()
+        .find_map(|key| {
+            ChaCha20Poly1305::new(key)
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .ok()
+        })
+        .ok_or(CliError::DecryptionError)
+}
+
+/// Decrypts the rest of a `.crypt` file starting with `preamble` from `reader` into `writer`
+fn open<R: Read, W: Write>(
+    secret: &Secret,
+    preamble: Preamble,
+    reader: &mut R,
+    writer: &mut W,
+    input_name: &str,
+    output_name: &str,
+) -> Result<(), CliError> {
+    match preamble {
+        Preamble::Legacy(data) => {
+            let keys = match secret {
+                Secret::Keys(keys) | Secret::Agent { keys, .. } => keys,
+                _ => return Err(CliError::KeyRequired),
+            };
+            let plaintext = open_legacy(keys, &data)?;
+            writer
+                .write_all(&plaintext)
+                .and_then(|_| writer.flush())
+                .map_err(|_| CliError::FileWriteError(output_name.to_string()))
+        }
+        Preamble::Header(header) => {
+            let key = resolve_key(secret, &header)?;
+            Stream::new(
+                &*header.cipher.new_cipher(&key),
+                &header.nonce_prefix,
+                header.chunk_len,
+                &header.aad(),
+            )
+            .decrypt(reader, writer, input_name, output_name)
+        }
+    }
 }
 
 fn encrypt(
+    path_args: PathArgs,
+    key_args: KeyArgs,
+    recipients: Vec<String>,
+    recipient_files: Vec<String>,
+    encrypt_args: EncryptArgs,
+) -> Result<(), CliError> {
+    let format = encrypt_args.format;
+    let unsupported = match format {
+        Format::Crypt if encrypt_args.armor => Some("--armor"),
+        Format::Age if encrypt_args.cipher.is_some() => Some("--cipher"),
+        Format::Age if encrypt_args.memory_cost.is_some() => Some("--memory-cost"),
+        Format::Age if encrypt_args.time_cost.is_some() => Some("--time-cost"),
+        _ => None,
+    };
+    if let Some(flag) = unsupported {
+        return Err(CliError::UnsupportedByFormat(flag.to_string()));
+    }
+    let paths = match path_args.recursive {
+        true => path_args.dirs().map(|_| None)?,
+        false => Some(path_args.paths(format, true)?),
+    };
+    let mut recipients = recipients
+        .iter()
+        .map(|recipient| Recipient::decode(recipient))
+        .collect::<Result<Vec<_>, _>>()?;
+    for recipient_file in &recipient_files {
+        let (decoded, skipped) = Recipient::decode_file(&read_bytes(recipient_file)?)?;
+        for kind in &skipped {
+            eprintln!("{}: skipped unsupported key type {}", recipient_file, kind);
+        }
+        if decoded.is_empty() {
+            return Err(CliError::NoRecipients(recipient_file.clone()));
+        }
+        recipients.extend(decoded);
+    }
+    let secret = match recipients.is_empty() || key_args.has_keys() {
+        true => get_secret(key_args, true)?,
+        false => Secret::Keys(Vec::new()),
+    };
+
+    match paths {
+        Some(paths) => encrypt_file(&secret, &recipients, &encrypt_args, &paths),
+        None => process_tree(&path_args, format, true, |paths| {
+            encrypt_file(&secret, &recipients, &encrypt_args, paths)
+        }),
+    }
+}
+
+/// Encrypts the input of `paths` into its output
+fn encrypt_file(
+    secret: &Secret,
+    recipients: &[Recipient],
+    encrypt_args: &EncryptArgs,
+    paths: &Paths,
+) -> Result<(), CliError> {
+    let (input_name, output_name) = (paths.input_name(), paths.output_name());
+    let cipher = encrypt_args.cipher.unwrap_or(CipherId::XChaCha20Poly1305);
+    if encrypt_args.format == Format::Age {
+        let password = match secret {
+            Secret::Keys(keys) if keys.is_empty() => None,
+            Secret::Password(password) => Some(password.as_str()),
+            _ => {
+                return Err(CliError::UnsupportedByFormat(
+                    "--key and --key-file".to_string(),
+                ))
+            }
+        };
+        let mut reader = open_input(paths.input.as_ref())?;
+        return write_output(paths, |mut writer| {
+            age::encrypt(
+                recipients,
+                password,
+                encrypt_args.armor,
+                &mut reader,
+                &mut writer,
+                &input_name,
+                &output_name,
+            )
+        });
+    }
+
+    // Keys wrap a random payload key so that they can be rotated with `rekey`, while a
+    // password derives the payload key itself
+    let (key, header) = match secret {
+        Secret::Keys(keys) => envelope_header(keys, recipients, cipher)?,
+        Secret::Password(password) => {
+            let params = Argon2Params::new(
+                encrypt_args.memory_cost.unwrap_or(kdf::DEFAULT_MEMORY_COST),
+                encrypt_args.time_cost.unwrap_or(kdf::DEFAULT_TIME_COST),
+            )?;
+            let key = params.derive_key(password)?;
+            (key, new_header(&key, Kdf::Argon2id(params), cipher))
+        }
+        Secret::Agent { keys, names, agent } => {
+            let (key, mut header) = envelope_header(keys, recipients, cipher)?;
+            for name in names {
+                header.stanzas.push(agent.wrap(&key, name)?);
+            }
+            (key, header)
+        }
+        Secret::Identities { .. } => unreachable!("identities are only read when decrypting"),
+    };
+
+    let mut reader = open_input(paths.input.as_ref())?;
+    write_output(paths, |mut writer| {
+        seal(
+            &key,
+            header,
+            &mut reader,
+            &mut writer,
+            &input_name,
+            &output_name,
+        )
+    })
+}
+
+/// Whether an agent or a keyring exists to look up the keys of files in
+fn has_key_store() -> bool {
+    Agent::from_env().is_some() || keyring::path().is_ok_and(|path| Path::new(&path).exists())
+}
+
+/// Whether the `.crypt` file starting with `preamble` was encrypted with keys or to recipients
+/// rather than with a password
+fn needs_key(preamble: &Preamble) -> bool {
+    match preamble {
+        Preamble::Legacy(_) => true,
+        Preamble::Header(header) => !matches!(header.kdf, Kdf::Argon2id(_)),
+    }
+}
+
+/// The error for a file starting with `preamble` that needs a key when none was given
+fn key_required(preamble: &Preamble) -> CliError {
+    match preamble {
+        Preamble::Header(header)
+            if header
+                .stanzas
+                .iter()
+                .any(|stanza| stanza.kind != recipient::STANZA_KEY) =>
+        {
+            CliError::IdentityRequired
+        }
+        _ => CliError::KeyRequired,
+    }
+}
+
+/// Reads the secret to decrypt with. `from_store` looks keys up in the agent or the keyring
+/// when none is given, as for files encrypted with keys.
+fn decryption_secret(
+    key_args: KeyArgs,
+    identities: &[String],
+    from_store: bool,
+) -> Result<Secret, CliError> {
+    let no_secret = !key_args.has_secret();
+    Ok(match identities.is_empty() {
+        // Files encrypted with keys find theirs in the agent or the keyring by the key ids in
+        // the header
+        true if no_secret && from_store => match Agent::from_env() {
+            Some(agent) => Secret::Agent {
+                keys: Vec::new(),
+                names: Vec::new(),
+                agent,
+            },
+            None => Secret::Keys(
+                unlock_keyring(key_args.keyring_passphrase_fd, false)?
+                    .0
+                    .keys(),
+            ),
+        },
+        true => get_secret(key_args, false)?,
+        false => {
+            let (keys, agent) = match key_args.has_keys() {
+                true => match get_secret(key_args, false)? {
+                    Secret::Keys(keys) => (keys, None),
+                    Secret::Agent { keys, agent, .. } => (keys, Some(agent)),
+                    _ => unreachable!("identities conflict with passwords"),
+                },
+                false => (Vec::new(), None),
+            };
+            Secret::Identities {
+                keys,
+                identities: identities
+                    .iter()
+                    .map(|identity| Identity::decode(&read_bytes(identity)?))
+                    .collect::<Result<_, _>>()?,
+                agent,
+            }
+        }
+    })
+}
+
+fn decrypt(
+    path_args: PathArgs,
+    key_args: KeyArgs,
+    identities: Vec<String>,
+    format: Format,
+) -> Result<(), CliError> {
+    if path_args.recursive {
+        path_args.dirs()?;
+        let from_store = format == Format::Crypt && has_key_store();
+        let secret = decryption_secret(key_args, &identities, from_store)?;
+        return process_tree(&path_args, format, false, |paths| {
+            let (mut reader, preamble) = open_encrypted(paths, format)?;
+            decrypt_file(&secret, preamble, &mut reader, paths)
+        });
+    }
+
+    let paths = path_args.paths(format, false)?;
+    let (mut reader, preamble) = open_encrypted(&paths, format)?;
+    let key_preamble = preamble.as_ref().filter(|preamble| needs_key(preamble));
+    let from_store = key_preamble.is_some() && has_key_store();
+    if let Some(preamble) = key_preamble {
+        // There is nothing to prompt for, the file cannot have been encrypted with a password
+        if !from_store && !key_args.has_secret() && identities.is_empty() {
+            return Err(key_required(preamble));
+        }
+    }
+    let secret = decryption_secret(key_args, &identities, from_store)?;
+    decrypt_file(&secret, preamble, &mut reader, &paths)
+}
+
+/// Opens the input of `paths`, reading the preamble of a `.crypt` file
+fn open_encrypted(
+    paths: &Paths,
+    format: Format,
+) -> Result<(Box<dyn BufRead>, Option<Preamble>), CliError> {
+    let mut reader = open_input(paths.input.as_ref())?;
+    let preamble = match format {
+        Format::Crypt => Some(read_preamble(&mut reader, &paths.input_name())?),
+        Format::Age => None,
+    };
+    Ok((reader, preamble))
+}
+
+/// Decrypts `reader` into the output of `paths`, as a `.crypt` file starting with `preamble`
+/// or an age file without it
+fn decrypt_file(
+    secret: &Secret,
+    preamble: Option<Preamble>,
+    reader: &mut Box<dyn BufRead>,
+    paths: &Paths,
+) -> Result<(), CliError> {
+    let (input_name, output_name) = (paths.input_name(), paths.output_name());
+    if let Some(preamble) = preamble {
+        return write_output(paths, |mut writer| {
+            open(
+                secret,
+                preamble,
+                reader,
+                &mut writer,
+                &input_name,
+                &output_name,
+            )
+        });
+    }
+
+    let (identities, password) = match secret {
+        Secret::Identities {
+            keys,
+            identities,
+            agent: None,
+        } if keys.is_empty() => (identities.as_slice(), None),
+        Secret::Password(password) => ([].as_slice(), Some(password.as_str())),
+        _ => {
+            return Err(CliError::UnsupportedByFormat(
+                "--key and --key-file".to_string(),
+            ))
+        }
+    };
+    write_output(paths, |mut writer| {
+        age::decrypt(
+            identities,
+            password,
+            reader,
+            &mut writer,
+            &input_name,
+            &output_name,
+        )
+    })
+}
+
+/// Encrypts, or decrypts when `encrypting` is unset, every file below the input directory of
+/// `path_args` with `process`, mirroring the tree into the output directory when one is given
+/// and writing next to each file otherwise. Files already ending in the suffix are skipped when
+/// encrypting and the others when decrypting, as are symbolic links.
+fn process_tree<F>(
+    path_args: &PathArgs,
+    format: Format,
+    encrypting: bool,
+    mut process: F,
+) -> Result<(), CliError>
+where
+    F: FnMut(&Paths) -> Result<(), CliError>,
+{
+    let (input_dir, output_dir) = path_args.dirs()?;
+    let suffix = path_args.suffix(format);
+
+    let (mut processed, mut skipped, mut failed) = (0, 0, 0);
+    for entry in tree::walk(input_dir, output_dir) {
+        let input = match entry {
+            tree::Entry::File(input) => input,
+            tree::Entry::Skipped(path, reason) => {
+                skipped += 1;
+                eprintln!("{}: skipped, {}", path.display(), reason);
+                continue;
+            }
+            tree::Entry::Unreadable(path) => {
+                failed += 1;
+                let error = CliError::FileReadError(path.display().to_string());
+                eprintln!("{}: ERROR: {}", path.display(), error);
+                continue;
+            }
+        };
+
+        let input_name = input.display().to_string();
+        let output_name = match (encrypting, input_name.strip_suffix(&suffix)) {
+            (true, None) => format!("{}{}", input_name, suffix),
+            (false, Some(output)) if !output.ends_with(std::path::MAIN_SEPARATOR) => {
+                output.to_string()
+            }
+            (true, Some(_)) | (false, _) => {
+                skipped += 1;
+                let reason = match encrypting {
+                    true => "already encrypted",
+                    false => "not encrypted",
+                };
+                eprintln!("{}: skipped, {}", input_name, reason);
+                continue;
+            }
+        };
+        let output_name = match output_dir {
+            Some(output_dir) => {
+                let relative = Path::new(&output_name)
+                    .strip_prefix(input_dir)
+                    .expect("walked files are below the input directory");
+                output_dir.join(relative).display().to_string()
+            }
+            None => output_name,
+        };
+
+        let result = Path::new(&output_name)
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .map_err(|_| CliError::FileWriteError(output_name.clone()))
+            .and_then(|_| {
+                Paths::new(
+                    Some(input_name.clone()),
+                    Some(output_name),
+                    path_args.overwrite(),
+                )
+            })
+            .and_then(|paths| process(&paths));
+        match result {
+            Ok(()) => {
+                processed += 1;
+                let done = match encrypting {
+                    true => "encrypted",
+                    false => "decrypted",
+                };
+                eprintln!("{}: {}", input_name, done);
+            }
+            Err(error) => {
+                failed += 1;
+                eprintln!("{}: ERROR: {}", input_name, error);
+            }
+        }
+    }
+
+    eprintln!(
+        "{} processed, {} skipped, {} failed",
+        processed, skipped, failed
+    );
+    match failed {
+        0 => Ok(()),
+        failed => Err(CliError::TreeError(failed)),
+    }
+}
+
+fn keygen(file_name: String, encoding: KeyEncoding, x25519: bool) -> Result<(), CliError> {
+    if x25519 {
+        let identity = StaticSecret::random_from_rng(rand::thread_rng());
+        let public = recipient::encode_public(&PublicKey::from(&identity));
+        let public_name = format!("{}.pub", file_name);
+        // Neither file is written when either exists, leaving no identity without its key
+        if let Some(existing) = [&file_name, &public_name]
+            .into_iter()
+            .find(|name| Path::new(name).exists())
+        {
+            return Err(CliError::OutputExists(existing.clone()));
+        }
+        keyfile::write_new_secret(
+            &file_name,
+            format!("{}\n", recipient::encode_identity(&identity)).as_bytes(),
+        )?;
+        keyfile::write_new(&public_name, format!("{}\n", public).as_bytes(), 0o644).inspect_err(
+            |_| {
+                let _ = std::fs::remove_file(&file_name);
+            },
+        )?;
+        eprintln!("Public key: {}", public);
+        return Ok(());
+    }
+
+    let key: Key = rand::thread_rng().gen::<[u8; KEY_LEN]>().into();
+    keyfile::write_new_secret(&file_name, &keyfile::encode(&key, encoding))?;
+    eprintln!("Key id: {}", hex::encode(keyfile::key_id(&key)));
+    Ok(())
+}
+
+fn split_key(
     file_name: String,
-    raw_key: Option<String>,
+    threshold: u8,
+    shares: u8,
+    key_encoding: Option<KeyEncoding>,
+) -> Result<(), CliError> {
+    let key = get_key(None, Some(file_name.clone()), key_encoding)?;
+    for share in shamir::split(&key, threshold, shares)? {
+        let share_name = format!("{}.share{}", file_name, share.index);
+        keyfile::write_new_secret(
+            &share_name,
+            format!("{}\n", shamir::encode(&share)).as_bytes(),
+        )?;
+        eprintln!("{}: share {} of {}", share_name, share.index, shares);
+    }
+    Ok(())
+}
+
+fn combine_key(
+    share_files: Vec<String>,
+    file_name: String,
+    encoding: KeyEncoding,
+) -> Result<(), CliError> {
+    let shares = share_files
+        .iter()
+        .map(|share_file| shamir::decode(&read_bytes(share_file)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = shamir::combine(&shares)?;
+    keyfile::write_new_secret(&file_name, &keyfile::encode(&key, encoding))?;
+    eprintln!("Key id: {}", hex::encode(keyfile::key_id(&key)));
+    Ok(())
+}
+
+fn export_key(
+    file_name: String,
+    mnemonic: bool,
+    encoding: KeyEncoding,
+    key_encoding: Option<KeyEncoding>,
+) -> Result<(), CliError> {
+    let key = get_key(None, Some(file_name), key_encoding)?;
+    let exported = match mnemonic {
+        true => format!("{}\n", mnemonic::encode(&key)).into_bytes(),
+        false => keyfile::encode(&key, encoding),
+    };
+    std::io::stdout()
+        .write_all(&exported)
+        .map_err(|_| CliError::FileWriteError(STDOUT_NAME.to_string()))?;
+    eprintln!("Key id: {}", hex::encode(keyfile::key_id(&key)));
+    Ok(())
+}
+
+/// Reads a key typed on a terminal: a single line, or for a mnemonic lines until all the
+/// words have been given, as the export spreads them over several lines
+fn read_terminal_lines(
+    reader: &mut impl BufRead,
+    data: &mut String,
+    mnemonic: bool,
+) -> std::io::Result<()> {
+    while reader.read_line(data)? > 0
+        && mnemonic
+        && data.split_whitespace().count() < mnemonic::WORD_COUNT
+    {}
+    Ok(())
+}
+
+fn import_key(
+    file_name: String,
+    mnemonic: bool,
+    encoding: KeyEncoding,
+    key_encoding: Option<KeyEncoding>,
+) -> Result<(), CliError> {
+    let stdin = std::io::stdin();
+    let mut data = String::new();
+    if stdin.is_terminal() {
+        eprint!("{}: ", if mnemonic { "Words" } else { "Key" });
+        read_terminal_lines(&mut stdin.lock(), &mut data, mnemonic)
+    } else {
+        stdin.lock().read_to_string(&mut data).map(|_| ())
+    }
+    .map_err(|_| CliError::FileReadError(STDIN_NAME.to_string()))?;
+
+    let key = match mnemonic {
+        true => mnemonic::decode(&data)?,
+        false => keyfile::decode(data.trim_end_matches(['\r', '\n']).as_bytes(), key_encoding)?,
+    };
+    keyfile::write_new_secret(&file_name, &keyfile::encode(&key, encoding))?;
+    eprintln!("Key id: {}", hex::encode(keyfile::key_id(&key)));
+    Ok(())
+}
+
+fn keyring_add(
+    name: String,
     key_file: Option<String>,
+    key_encoding: Option<KeyEncoding>,
+    passphrase_fd: Option<i32>,
 ) -> Result<(), CliError> {
-    let cipher = ChaCha20Poly1305::new(&get_key(raw_key, key_file)?);
-    let nonce = new_rand_nonce();
-    let ciphertext = cipher
-        .encrypt(Nonce::from_slice(&nonce), read_bytes(&file_name)?.as_ref())
-        .map_err(|_| CliError::EncryptionError)?;
-    write_bytes(
-        &format!("{}.crypt", &file_name),
-        [nonce, ciphertext].concat(),
-    )
+    let key = match key_file {
+        Some(key_file) => get_key(None, Some(key_file), key_encoding)?,
+        None => rand::thread_rng().gen::<[u8; KEY_LEN]>().into(),
+    };
+    let (mut keyring, password) = unlock_keyring(passphrase_fd, true)?;
+    keyring.add(name, key)?;
+    keyring.save(&keyring::path()?, &password)?;
+    eprintln!("Key id: {}", hex::encode(keyfile::key_id(&key)));
+    Ok(())
 }
 
-fn decrypt(
+fn keyring_list(passphrase_fd: Option<i32>) -> Result<(), CliError> {
+    let (keyring, _) = unlock_keyring(passphrase_fd, false)?;
+    for (name, key) in keyring.entries() {
+        println!("{} {}", name, hex::encode(keyfile::key_id(key)));
+    }
+    Ok(())
+}
+
+fn keyring_remove(name: String, passphrase_fd: Option<i32>) -> Result<(), CliError> {
+    let (mut keyring, password) = unlock_keyring(passphrase_fd, false)?;
+    let key = keyring.remove(&name)?;
+    keyring.save(&keyring::path()?, &password)?;
+    eprintln!("Key id: {}", hex::encode(keyfile::key_id(&key)));
+    Ok(())
+}
+
+fn keyring_export(
+    name: String,
     file_name: String,
+    encoding: KeyEncoding,
+    passphrase_fd: Option<i32>,
+) -> Result<(), CliError> {
+    let (keyring, _) = unlock_keyring(passphrase_fd, false)?;
+    let key = keyring.get(&name)?;
+    keyfile::write_new_secret(&file_name, &keyfile::encode(&key, encoding))?;
+    eprintln!("Key id: {}", hex::encode(keyfile::key_id(&key)));
+    Ok(())
+}
+
+fn run_agent(
+    socket: Option<String>,
+    timeout: u64,
+    passphrase_fd: Option<i32>,
+    foreground: bool,
+) -> Result<(), CliError> {
+    let (keyring, _) = unlock_keyring(passphrase_fd, false)?;
+    let (socket, dir) = match socket {
+        Some(socket) => (socket, None),
+        None => {
+            let dir = std::env::temp_dir().join(format!("crypt-agent-{}", std::process::id()));
+            let mut builder = std::fs::DirBuilder::new();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::DirBuilderExt;
+                builder.mode(0o700);
+            }
+            let socket = dir.join("agent.sock").to_string_lossy().into_owned();
+            builder
+                .create(&dir)
+                .map_err(|_| CliError::AgentSocketError(socket.clone()))?;
+            (socket, Some(dir))
+        }
+    };
+
+    let remove_dir = || {
+        if let Some(dir) = &dir {
+            let _ = std::fs::remove_dir(dir);
+        }
+    };
+    let listener = agent::bind(&socket).inspect_err(|_| remove_dir())?;
+    // The parent only hands the socket to the shell, leaving it to the child
+    let parent = match foreground {
+        true => false,
+        false => agent::detach().inspect_err(|_| {
+            let _ = std::fs::remove_file(&socket);
+            remove_dir();
+        })?,
+    };
+    println!(
+        "{}={}; export {};",
+        agent::SOCKET_ENV,
+        socket,
+        agent::SOCKET_ENV
+    );
+    std::io::stdout()
+        .flush()
+        .map_err(|_| CliError::FileWriteError(STDOUT_NAME.to_string()))?;
+    if parent {
+        return Ok(());
+    }
+
+    agent::serve(
+        &keyring,
+        listener,
+        &socket,
+        std::time::Duration::from_secs(timeout),
+    );
+    remove_dir();
+    Ok(())
+}
+
+/// Outcome of migrating a single file
+enum Migration {
+    Migrated,
+    AlreadyCurrent,
+}
+
+fn migrate_file(key: &Key, file_name: &String) -> Result<Migration, CliError> {
+    let mut reader = open_reader(file_name)?;
+    let data = match read_preamble(&mut reader, file_name)? {
+        Preamble::Legacy(data) => data,
+        Preamble::Header(..) => return Ok(Migration::AlreadyCurrent),
+    };
+    drop(reader);
+
+    let plaintext = open_legacy(&[*key], &data)?;
+    let (file_key, header) = envelope_header(&[*key], &[], CipherId::XChaCha20Poly1305)?;
+    write_replacing(file_name, Overwrite::Replace, 0o666, |writer| {
+        seal(
+            &file_key,
+            header,
+            &mut plaintext.as_slice(),
+            writer,
+            file_name,
+            file_name,
+        )
+    })?;
+    Ok(Migration::Migrated)
+}
+
+fn migrate(
+    files: Vec<String>,
     raw_key: Option<String>,
     key_file: Option<String>,
+    key_encoding: Option<KeyEncoding>,
+) -> Result<(), CliError> {
+    let key = get_key(raw_key, key_file, key_encoding)?;
+    let mut failed = 0;
+    for file_name in &files {
+        match migrate_file(&key, file_name) {
+            Ok(Migration::Migrated) => eprintln!("{}: migrated", file_name),
+            Ok(Migration::AlreadyCurrent) => eprintln!("{}: already current", file_name),
+            Err(error) => {
+                failed += 1;
+                eprintln!("{}: ERROR: {}", file_name, error);
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(CliError::MigrationError(failed)),
+    }
+}
+
+fn rekey_file(old_key: &Key, new_key: &Key, file_name: &String) -> Result<(), CliError> {
+    let mut reader = open_reader(file_name)?;
+    let mut header = match read_preamble(&mut reader, file_name)? {
+        Preamble::Header(header) if header.has_recipients() => header,
+        _ => return Err(CliError::NotEnveloped),
+    };
+
+    let (index, file_key) = header
+        .stanzas
+        .iter()
+        .enumerate()
+        .find_map(|(i, stanza)| Some((i, recipient::unwrap_key(stanza, old_key)?)))
+        .ok_or(CliError::OldKeyNotFound)?;
+    recipient::verify_header_mac(&file_key, &header)?;
+    if keyfile::key_id(&file_key) != header.key_id {
+        return Err(CliError::HeaderAuthError);
+    }
+
+    // A file already wrapped with the new key only loses its stanza for the old one
+    let new_key_id = keyfile::key_id(new_key);
+    let has_new_key = header
+        .stanzas
+        .iter()
+        .enumerate()
+        .any(|(i, stanza)| i != index && recipient::stanza_key_id(stanza) == Some(new_key_id));
+    match has_new_key {
+        true => {
+            header.stanzas.remove(index);
+        }
+        false => header.stanzas[index] = recipient::wrap_key(&file_key, new_key)?,
+    }
+    header.mac = recipient::header_mac(&file_key, &header);
+    write_replacing(file_name, Overwrite::Replace, 0o666, |writer| {
+        writer
+            .write_all(&header.to_bytes())
+            .map_err(|_| CliError::FileWriteError(file_name.clone()))?;
+        std::io::copy(&mut reader, writer)
+            .map_err(|_| CliError::FileReadError(file_name.clone()))?;
+        writer
+            .flush()
+            .map_err(|_| CliError::FileWriteError(file_name.clone()))
+    })
+}
+
+fn rekey(
+    files: Vec<String>,
+    old_key_file: String,
+    new_key_file: String,
+    key_encoding: Option<KeyEncoding>,
 ) -> Result<(), CliError> {
-    let cipher = ChaCha20Poly1305::new(&get_key(raw_key, key_file)?);
-    let data = read_bytes(&format!("{}.crypt", &file_name))?;
-    let nonce = Nonce::from_slice(&data[..NONCE_LEN]);
-    let plaintext = cipher
-        .decrypt(&nonce, &data[NONCE_LEN..])
-        .map_err(|_| CliError::DecryptionError)?;
-    write_bytes(&file_name, plaintext)
+    let old_key = get_key(None, Some(old_key_file), key_encoding)?;
+    let new_key = get_key(None, Some(new_key_file), key_encoding)?;
+    let mut failed = 0;
+    for file_name in &files {
+        match rekey_file(&old_key, &new_key, file_name) {
+            Ok(()) => eprintln!("{}: rekeyed", file_name),
+            Err(error) => {
+                failed += 1;
+                eprintln!("{}: ERROR: {}", file_name, error);
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(CliError::RekeyError(failed)),
+    }
 }
 
 fn main() {
@@ -141,19 +1982,413 @@ fn main() {
 
     let result = match cli.command {
         Command::En {
-            file,
-            key,
-            key_file,
-        } => encrypt(file, key, key_file),
+            path_args,
+            key_args,
+            recipient,
+            recipient_file,
+            encrypt_args,
+        } => encrypt(path_args, key_args, recipient, recipient_file, encrypt_args),
         Command::De {
+            path_args,
+            key_args,
+            identity,
+            format,
+        } => decrypt(path_args, key_args, identity, format),
+        Command::Keygen {
             file,
+            encoding,
+            x25519,
+        } => keygen(file, encoding, x25519),
+        Command::Key { command } => match command {
+            KeyCommand::Split {
+                file,
+                threshold,
+                shares,
+                key_encoding,
+            } => split_key(file, threshold, shares, key_encoding),
+            KeyCommand::Combine {
+                shares,
+                file,
+                encoding,
+            } => combine_key(shares, file, encoding),
+            KeyCommand::Export {
+                file,
+                mnemonic,
+                encoding,
+                key_encoding,
+            } => export_key(file, mnemonic, encoding, key_encoding),
+            KeyCommand::Import {
+                file,
+                mnemonic,
+                encoding,
+                key_encoding,
+            } => import_key(file, mnemonic, encoding, key_encoding),
+        },
+        Command::Keyring { command } => match command {
+            KeyringCommand::Add {
+                name,
+                key_file,
+                key_encoding,
+                passphrase_fd,
+            } => keyring_add(name, key_file, key_encoding, passphrase_fd),
+            KeyringCommand::List { passphrase_fd } => keyring_list(passphrase_fd),
+            KeyringCommand::Remove {
+                name,
+                passphrase_fd,
+            } => keyring_remove(name, passphrase_fd),
+            KeyringCommand::Export {
+                name,
+                file,
+                encoding,
+                passphrase_fd,
+            } => keyring_export(name, file, encoding, passphrase_fd),
+        },
+        Command::Agent {
+            socket,
+            timeout,
+            passphrase_fd,
+            foreground,
+        } => run_agent(socket, timeout, passphrase_fd, foreground),
+        Command::Rekey {
+            files,
+            old_key_file,
+            new_key_file,
+            key_encoding,
+        } => rekey(files, old_key_file, new_key_file, key_encoding),
+        Command::Migrate {
+            files,
             key,
             key_file,
-        } => decrypt(file, key, key_file),
+            key_encoding,
+        } => migrate(files, key, key_file, key_encoding),
     };
 
     match result {
-        Ok(_) => println!("SUCCESS"),
-        Err(error) => println!("ERROR: {}", error),
+        Ok(_) => eprintln!("SUCCESS"),
+        Err(error) => {
+            eprintln!("ERROR: {}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_dir;
+
+    #[test]
+    fn identities_with_agent_key_names() {
+        let dir = temp_dir("identities-agent");
+        let identity = dir.join("identity").display().to_string();
+        let secret = StaticSecret::random_from_rng(rand::thread_rng());
+        std::fs::write(&identity, recipient::encode_identity(&secret)).unwrap();
+        std::env::set_var(agent::SOCKET_ENV, dir.join("agent.sock"));
+
+        let cli = Cli::try_parse_from([
+            "crypt",
+            "de",
+            "-i",
+            "file.crypt",
+            "--identity",
+            &identity,
+            "--key-name",
+            "prod",
+        ])
+        .unwrap();
+        let (key_args, identities) = match cli.command {
+            Command::De {
+                key_args, identity, ..
+            } => (key_args, identity),
+            _ => unreachable!(),
+        };
+        let secret = decryption_secret(key_args, &identities, false).unwrap();
+        std::env::remove_var(agent::SOCKET_ENV);
+        assert!(matches!(
+            secret,
+            Secret::Identities {
+                agent: Some(_),
+                ref identities,
+                ..
+            } if identities.len() == 1
+        ));
+    }
+
+    #[test]
+    fn terminal_import_reads_every_mnemonic_line() {
+        let words = mnemonic::encode(&crate::testutil::key(5));
+        let mut input = format!("{}\nextra\n", words);
+        let mut data = String::new();
+        read_terminal_lines(&mut input.as_bytes(), &mut data, true).unwrap();
+        assert_eq!(data, format!("{}\n", words));
+
+        input = "0123\nextra\n".to_string();
+        data.clear();
+        read_terminal_lines(&mut input.as_bytes(), &mut data, false).unwrap();
+        assert_eq!(data, "0123\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secret_outputs_are_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("secret-mode");
+        let file_name = dir.join("keyring").display().to_string();
+        let mode = || std::fs::metadata(&file_name).unwrap().permissions().mode() & 0o777;
+
+        write_replacing(&file_name, Overwrite::Replace, 0o600, |_| Ok(())).unwrap();
+        assert_eq!(mode(), 0o600);
+
+        std::fs::set_permissions(&file_name, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_replacing(&file_name, Overwrite::Replace, 0o600, |_| Ok(())).unwrap();
+        assert_eq!(mode(), 0o600);
+    }
+
+    #[test]
+    fn key_required_names_what_is_missing() {
+        let recipient = Recipient::X25519(PublicKey::from(&StaticSecret::random_from_rng(
+            rand::thread_rng(),
+        )));
+        let keys = [crate::testutil::key(1)];
+        let cipher = CipherId::XChaCha20Poly1305;
+
+        let (_, header) = envelope_header(&keys, &[], cipher).unwrap();
+        assert!(matches!(
+            key_required(&Preamble::Header(header)),
+            CliError::KeyRequired
+        ));
+        let (_, header) = envelope_header(&keys, &[recipient], cipher).unwrap();
+        assert!(matches!(
+            key_required(&Preamble::Header(header)),
+            CliError::IdentityRequired
+        ));
+        assert!(matches!(
+            key_required(&Preamble::Legacy(Vec::new())),
+            CliError::KeyRequired
+        ));
+    }
+
+    /// Encrypts `plaintext` with `keys` into `file_name`
+    fn seal_file(file_name: &String, keys: &[Key], plaintext: &[u8]) {
+        let (key, header) = envelope_header(keys, &[], CipherId::XChaCha20Poly1305).unwrap();
+        let mut data = Vec::new();
+        seal(&key, header, &mut &plaintext[..], &mut data, "", "").unwrap();
+        std::fs::write(file_name, data).unwrap();
+    }
+
+    /// Decrypts `file_name` with `keys`
+    fn open_file(file_name: &String, keys: &[Key]) -> Result<Vec<u8>, CliError> {
+        let data = std::fs::read(file_name).unwrap();
+        let mut reader = data.as_slice();
+        let preamble = read_preamble(&mut reader, file_name)?;
+        let mut plaintext = Vec::new();
+        open(
+            &Secret::Keys(keys.to_vec()),
+            preamble,
+            &mut reader,
+            &mut plaintext,
+            "",
+            "",
+        )?;
+        Ok(plaintext)
+    }
+
+    /// The bytes of `file_name` following its header
+    fn payload(file_name: &String) -> Vec<u8> {
+        let data = std::fs::read(file_name).unwrap();
+        let mut reader = data.as_slice();
+        read_preamble(&mut reader, file_name).unwrap();
+        reader.to_vec()
+    }
+
+    #[test]
+    fn rekey_replaces_the_key_only() {
+        let dir = temp_dir("rekey");
+        let file_name = dir.join("file.crypt").display().to_string();
+        let (old_key, new_key) = (crate::testutil::key(1), crate::testutil::key(3));
+        seal_file(&file_name, &[old_key], b"rekeyed");
+        let before = payload(&file_name);
+
+        rekey_file(&old_key, &new_key, &file_name).unwrap();
+        assert_eq!(payload(&file_name), before);
+        assert!(matches!(
+            open_file(&file_name, &[old_key]),
+            Err(CliError::KeyMismatch(..))
+        ));
+        assert_eq!(open_file(&file_name, &[new_key]).unwrap(), b"rekeyed");
+    }
+
+    #[test]
+    fn rekey_to_a_present_key_drops_the_old_stanza() {
+        let dir = temp_dir("rekey-present");
+        let file_name = dir.join("file.crypt").display().to_string();
+        let (old_key, new_key) = (crate::testutil::key(1), crate::testutil::key(3));
+        seal_file(&file_name, &[old_key, new_key], b"rekeyed");
+        let before = payload(&file_name);
+
+        rekey_file(&old_key, &new_key, &file_name).unwrap();
+        let data = std::fs::read(&file_name).unwrap();
+        match read_preamble(&mut data.as_slice(), &file_name).unwrap() {
+            Preamble::Header(header) => assert_eq!(header.stanzas.len(), 1),
+            Preamble::Legacy(_) => panic!("rekeyed file lost its header"),
+        }
+        assert_eq!(payload(&file_name), before);
+        assert!(open_file(&file_name, &[old_key]).is_err());
+        // Opening checks the header MAC, recomputed over the remaining stanza
+        assert_eq!(open_file(&file_name, &[new_key]).unwrap(), b"rekeyed");
+    }
+
+    /// File laid out as `nonce || ciphertext` by releases before the header, holding
+    /// [`LEGACY_PLAINTEXT`] under `key(1)`
+    const LEGACY_FILE: &[u8] = include_bytes!("../tests/testdata/legacy.crypt");
+    const LEGACY_PLAINTEXT: &[u8] = b"Written before files had headers\n";
+
+    #[test]
+    fn opens_legacy_files() {
+        let key = crate::testutil::key(1);
+        assert_eq!(open_legacy(&[key], LEGACY_FILE).unwrap(), LEGACY_PLAINTEXT);
+        assert!(open_legacy(&[crate::testutil::key(3)], LEGACY_FILE).is_err());
+    }
+
+    #[test]
+    fn migrates_legacy_files() {
+        let dir = temp_dir("migrate");
+        let file_name = dir.join("legacy.crypt").display().to_string();
+        std::fs::write(&file_name, LEGACY_FILE).unwrap();
+        let key = crate::testutil::key(1);
+        assert_eq!(open_file(&file_name, &[key]).unwrap(), LEGACY_PLAINTEXT);
+
+        assert!(matches!(
+            migrate_file(&key, &file_name),
+            Ok(Migration::Migrated)
+        ));
+        let data = std::fs::read(&file_name).unwrap();
+        assert!(matches!(
+            read_preamble(&mut data.as_slice(), &file_name),
+            Ok(Preamble::Header(_))
+        ));
+        assert_eq!(open_file(&file_name, &[key]).unwrap(), LEGACY_PLAINTEXT);
+        assert!(matches!(
+            migrate_file(&key, &file_name),
+            Ok(Migration::AlreadyCurrent)
+        ));
+    }
+
+    /// Resolves the paths given by `args` as `en`, or `de` unless `encrypting`
+    fn paths(
+        args: &[&str],
+        encrypting: bool,
+    ) -> Result<(Option<String>, Option<String>), CliError> {
+        #[derive(Parser)]
+        struct PathCli {
+            #[clap(flatten)]
+            path_args: PathArgs,
+        }
+        let cli = PathCli::try_parse_from(std::iter::once(&"crypt").chain(args)).unwrap();
+        let paths = cli.path_args.paths(Format::Crypt, encrypting)?;
+        Ok((paths.input, paths.output))
+    }
+
+    #[test]
+    fn resolves_paths() {
+        let dir = temp_dir("paths");
+        let file = |name: &str| Some(dir.join(name).display().to_string());
+        let x = file("x").unwrap();
+        let x_crypt = file("x.crypt").unwrap();
+        let vendor = file("vendor.bin.enc").unwrap();
+
+        assert_eq!(
+            paths(&["-f", &x], true).unwrap(),
+            (file("x"), file("x.crypt"))
+        );
+        assert_eq!(
+            paths(&["-f", &x], false).unwrap(),
+            (file("x.crypt"), file("x"))
+        );
+        assert_eq!(
+            paths(&["-f", &x_crypt], false).unwrap(),
+            (file("x.crypt"), file("x"))
+        );
+        assert_eq!(
+            paths(&["--input", &vendor, "--suffix", "enc"], false).unwrap(),
+            (file("vendor.bin.enc"), file("vendor.bin"))
+        );
+        assert_eq!(paths(&["-f", "-"], true).unwrap(), (None, None));
+        assert_eq!(paths(&["-f", "-"], false).unwrap(), (None, None));
+        assert_eq!(
+            paths(&["-f", &x, "--stdout"], true).unwrap(),
+            (file("x"), None)
+        );
+    }
+
+    #[test]
+    fn refuses_colliding_paths() {
+        let dir = temp_dir("paths-collide");
+        let x = dir.join("x").display().to_string();
+        std::fs::write(&x, b"x").unwrap();
+
+        assert!(matches!(
+            paths(&["-i", &x], false),
+            Err(CliError::OutputUnknown(..))
+        ));
+        assert!(matches!(
+            paths(&["-i", &x, "-o", &x, "--force"], true),
+            Err(CliError::OutputIsInput(_))
+        ));
+        assert!(matches!(
+            paths(&["-i", &format!("{}.crypt", x), "-o", &x], false),
+            Err(CliError::OutputExists(_))
+        ));
+        assert!(paths(&["-i", &format!("{}.crypt", x), "-o", &x, "--force"], false).is_ok());
+    }
+
+    #[test]
+    fn refuses_or_backs_up_existing_outputs() {
+        let dir = temp_dir("overwrite");
+        let file_name = dir.join("out").display().to_string();
+        let write = |overwrite, data: &'static [u8]| {
+            write_replacing(&file_name, overwrite, 0o666, |writer| {
+                writer
+                    .write_all(data)
+                    .map_err(|_| CliError::FileWriteError(file_name.clone()))
+            })
+        };
+
+        write(Overwrite::Refuse, b"first").unwrap();
+        assert!(matches!(
+            write(Overwrite::Refuse, b"second"),
+            Err(CliError::OutputExists(_))
+        ));
+        assert_eq!(std::fs::read(&file_name).unwrap(), b"first");
+
+        write(Overwrite::Backup, b"second").unwrap();
+        write(Overwrite::Backup, b"third").unwrap();
+        assert_eq!(std::fs::read(&file_name).unwrap(), b"third");
+        assert_eq!(
+            std::fs::read(format!("{}.bak", file_name)).unwrap(),
+            b"second"
+        );
+        // Only the output and its backup are left, no temp files
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn combines_key_sources() {
+        let key_args = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["crypt", "de", "-i", "file.crypt"].iter().chain(args));
+            cli.ok().map(|cli| match cli.command {
+                Command::De { key_args, .. } => key_args,
+                _ => unreachable!(),
+            })
+        };
+
+        let combined = key_args(&["--key-file", "a", "--key-name", "prod", "--key", "00"]).unwrap();
+        assert_eq!(combined.key_file, ["a"]);
+        assert_eq!(combined.key_name, ["prod"]);
+        assert!(combined.has_keys());
+        assert!(key_args(&["--key-file", "a", "--password", "pw"]).is_none());
+        assert!(key_args(&["--key-name", "prod", "--passphrase-fd", "3"]).is_none());
+        assert!(key_args(&["--key", "00", "--password", "pw"]).is_none());
+        assert!(key_args(&["--identity", "id", "--password", "pw"]).is_none());
     }
 }
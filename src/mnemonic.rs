@@ -0,0 +1,116 @@
+use crate::{CliError, KEY_LEN};
+use bip39::{Language, Mnemonic};
+use chacha20poly1305::Key;
+
+/// Number of words encoding a key and the checksum byte of BIP39
+pub const WORD_COUNT: usize = (KEY_LEN * 8 + KEY_LEN / 4) / 11;
+
+/// Words printed on each line of an exported mnemonic
+const WORDS_PER_LINE: usize = 6;
+
+/// Encodes `key` as BIP39 English words, [`WORDS_PER_LINE`] to a line
+pub fn encode(key: &Key) -> String {
+    let mnemonic = Mnemonic::from_entropy(key).expect("key length is valid BIP39 entropy");
+    let words: Vec<&str> = mnemonic.words().collect();
+    words
+        .chunks(WORDS_PER_LINE)
+        .map(|line| line.join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Expands a word or an unambiguous prefix of one, such as the first four letters every word
+/// of the list is unique by
+fn expand(position: usize, word: &str) -> Result<&'static str, CliError> {
+    let language = Language::English;
+    if let Some(index) = language.find_word(word) {
+        return Ok(language.word_list()[index as usize]);
+    }
+    match language.words_by_prefix(word) {
+        [expanded] => Ok(expanded),
+        [] => Err(CliError::KeyDecodeError(format!(
+            "word {} \"{}\" is not in the word list",
+            position, word
+        ))),
+        candidates => Err(CliError::KeyDecodeError(format!(
+            "word {} \"{}\" is ambiguous ({})",
+            position,
+            word,
+            candidates.join(", ")
+        ))),
+    }
+}
+
+/// Decodes a key from its words, verifying the checksum to catch typos and swapped words
+pub fn decode(text: &str) -> Result<Key, CliError> {
+    let words = text
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| expand(i + 1, &word.to_lowercase()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if words.len() != WORD_COUNT {
+        return Err(CliError::KeyDecodeError(format!(
+            "expected {} words, found {}",
+            WORD_COUNT,
+            words.len()
+        )));
+    }
+
+    let mnemonic = Mnemonic::parse_in_normalized(Language::English, &words.join(" "))
+        .map_err(|_| CliError::KeyDecodeError("mnemonic checksum mismatch".to_string()))?;
+    Ok(*Key::from_slice(&mnemonic.to_entropy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::key;
+
+    #[test]
+    fn round_trip() {
+        let words = encode(&key(13));
+        assert_eq!(words.split_whitespace().count(), WORD_COUNT);
+        assert_eq!(words.lines().count(), WORD_COUNT / WORDS_PER_LINE);
+        assert_eq!(decode(&words).unwrap(), key(13));
+    }
+
+    #[test]
+    fn accepts_unique_prefixes() {
+        let prefixes: Vec<String> = encode(&key(13))
+            .split_whitespace()
+            .map(|word| word.chars().take(4).collect::<String>().to_uppercase())
+            .collect();
+        assert_eq!(decode(&prefixes.join(" ")).unwrap(), key(13));
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let encoded = encode(&key(13));
+        let mut words: Vec<&str> = encoded.split_whitespace().collect();
+        words.swap(0, 1);
+        assert!(matches!(
+            decode(&words.join(" ")),
+            Err(CliError::KeyDecodeError(reason)) if reason.contains("checksum")
+        ));
+    }
+
+    #[test]
+    fn rejects_ambiguous_prefix() {
+        let mut words: Vec<String> = encode(&key(13))
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        words[3] = "ab".to_string();
+        assert!(matches!(
+            decode(&words.join(" ")),
+            Err(CliError::KeyDecodeError(reason)) if reason.contains("ambiguous")
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_word_count() {
+        let words = encode(&key(13));
+        let (_, rest) = words.split_once(' ').unwrap();
+        assert!(decode(rest).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use crate::header::{Header, Stanza, MAC_LEN};
+use crate::keyfile::{self, KEY_ID_LEN};
+use crate::ssh::{self, SshIdentity, SshRecipient, SshWrapped, KEY_TAG_LEN};
+use crate::stream::TAG_LEN;
+use crate::{CliError, KEY_LEN};
+use bech32::{FromBase32, Variant};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::prelude::*;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+/// Stanza kind of a payload key wrapped to an X25519 public key
+pub const STANZA_X25519: u8 = 1;
+
+/// Stanza kind of a payload key wrapped with a symmetric key
+pub const STANZA_KEY: u8 = 2;
+
+/// Stanza kind of a payload key wrapped to an SSH ed25519 key
+pub const STANZA_SSH_ED25519: u8 = 3;
+
+/// Stanza kind of a payload key wrapped to an SSH RSA key
+pub const STANZA_SSH_RSA: u8 = 4;
+
+/// Length of the salt deriving the wrapping key of a symmetric stanza
+const WRAP_SALT_LEN: usize = 16;
+
+/// Prefix of an encoded X25519 public key
+const PUBLIC_PREFIX: &str = "crypt-x25519-pub-v1";
+
+/// Prefix of an encoded X25519 identity
+const IDENTITY_PREFIX: &str = "crypt-x25519-key-v1";
+
+pub fn encode_public(public: &PublicKey) -> String {
+    keyfile::encode_typed(PUBLIC_PREFIX, &[keyfile::encode_base64(public.as_bytes())])
+}
+
+/// Human readable part of an age X25519 recipient
+const AGE_PUBLIC_HRP: &str = "age";
+
+/// Human readable part of an age X25519 identity
+const AGE_IDENTITY_HRP: &str = "age-secret-key-";
+
+/// Decodes a bech32 encoded age key with the human readable part `hrp`
+fn decode_age(text: &str, hrp: &str) -> Result<[u8; KEY_LEN], CliError> {
+    let error = |reason: &str| CliError::KeyDecodeError(format!("invalid age key: {}", reason));
+    let (found_hrp, data, variant) = bech32::decode(text).map_err(|e| error(&e.to_string()))?;
+    if found_hrp != hrp || variant != Variant::Bech32 {
+        return Err(error("unexpected key type"));
+    }
+    Vec::<u8>::from_base32(&data)
+        .map_err(|e| error(&e.to_string()))?
+        .try_into()
+        .map_err(|_| error("wrong length"))
+}
+
+/// Decodes a public key, either in the typed format or as an age `age1...` recipient
+pub fn decode_public(text: &str) -> Result<PublicKey, CliError> {
+    let text = text.trim();
+    if text.starts_with("age1") {
+        return Ok(PublicKey::from(decode_age(text, AGE_PUBLIC_HRP)?));
+    }
+    match keyfile::decode_typed_fields(text, PUBLIC_PREFIX)?[..] {
+        [encoded] => Ok(PublicKey::from(keyfile::decode_base64_key(encoded)?)),
+        _ => Err(CliError::KeyDecodeError("malformed public key".to_string())),
+    }
+}
+
+pub fn encode_identity(identity: &StaticSecret) -> String {
+    keyfile::encode_typed(
+        IDENTITY_PREFIX,
+        &[keyfile::encode_base64(identity.as_bytes())],
+    )
+}
+
+/// Decodes an identity file, either in the typed format or as an age `AGE-SECRET-KEY-1...`
+/// identity, ignoring `#` comment lines
+pub fn decode_identity(data: &[u8]) -> Result<StaticSecret, CliError> {
+    let lines: Vec<&str> = keyfile::text(data)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let text = match lines[..] {
+        [text] => text,
+        _ => {
+            return Err(CliError::KeyDecodeError(
+                "expected one identity".to_string(),
+            ))
+        }
+    };
+    if text.starts_with("AGE-SECRET-KEY-1") {
+        return Ok(StaticSecret::from(decode_age(text, AGE_IDENTITY_HRP)?));
+    }
+    match keyfile::decode_typed_fields(text, IDENTITY_PREFIX)?[..] {
+        [encoded] => Ok(StaticSecret::from(keyfile::decode_base64_key(encoded)?)),
+        _ => Err(CliError::KeyDecodeError("malformed identity".to_string())),
+    }
+}
+
+/// Derives the key wrapping the payload key from an X25519 shared secret, bound to both public keys
+fn wrapping_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> Key {
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(b"crypt x25519", &mut key)
+        .expect("key length is valid for HKDF-SHA256");
+    key
+}
+
+/// Wraps `file_key` to `recipient` with an ephemeral X25519 key exchange
+///
+/// The stanza body is the ephemeral public key followed by the encrypted file
+/// key. Every wrapping key is used once, so the nonce is all zeros.
+fn wrap_x25519(file_key: &Key, recipient: &PublicKey) -> Result<Stanza, CliError> {
+    let ephemeral = EphemeralSecret::random_from_rng(rand::thread_rng());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(recipient);
+    if !shared.was_contributory() {
+        return Err(CliError::KeyDecodeError("invalid public key".to_string()));
+    }
+
+    let wrapped = ChaCha20Poly1305::new(&wrapping_key(
+        shared.as_bytes(),
+        &ephemeral_public,
+        recipient,
+    ))
+    .encrypt(Nonce::from_slice(&[0; 12]), file_key.as_slice())
+    .map_err(|_| CliError::EncryptionError)?;
+    Ok(Stanza {
+        kind: STANZA_X25519,
+        body: [ephemeral_public.as_bytes().as_slice(), &wrapped].concat(),
+    })
+}
+
+/// Unwraps the file key from `stanza` if it was wrapped to `identity`
+fn unwrap_x25519(stanza: &Stanza, identity: &StaticSecret) -> Option<Key> {
+    if stanza.kind != STANZA_X25519 || stanza.body.len() != KEY_LEN + KEY_LEN + TAG_LEN {
+        return None;
+    }
+    let (ephemeral_public, wrapped) = stanza.body.split_at(KEY_LEN);
+    let ephemeral_public = PublicKey::from(<[u8; KEY_LEN]>::try_from(ephemeral_public).ok()?);
+    let shared = identity.diffie_hellman(&ephemeral_public);
+    if !shared.was_contributory() {
+        return None;
+    }
+
+    let file_key = ChaCha20Poly1305::new(&wrapping_key(
+        shared.as_bytes(),
+        &ephemeral_public,
+        &PublicKey::from(identity),
+    ))
+    .decrypt(Nonce::from_slice(&[0; 12]), wrapped)
+    .ok()?;
+    Some(*Key::from_slice(&file_key))
+}
+
+/// Derives the key wrapping the payload key from a symmetric key and a per-stanza salt
+fn symmetric_wrapping_key(key: &Key, salt: &[u8]) -> Key {
+    let mut wrapping_key = Key::default();
+    Hkdf::<Sha256>::new(Some(salt), key)
+        .expand(b"crypt key wrap", &mut wrapping_key)
+        .expect("key length is valid for HKDF-SHA256");
+    wrapping_key
+}
+
+/// Wraps `file_key` with the symmetric `key`
+///
+/// The stanza body is the id of `key`, a random salt and the encrypted file
+/// key. The salt makes every wrapping key unique, so the nonce is all zeros.
+pub fn wrap_key(file_key: &Key, key: &Key) -> Result<Stanza, CliError> {
+    let salt: [u8; WRAP_SALT_LEN] = rand::thread_rng().gen();
+    let wrapped = ChaCha20Poly1305::new(&symmetric_wrapping_key(key, &salt))
+        .encrypt(Nonce::from_slice(&[0; 12]), file_key.as_slice())
+        .map_err(|_| CliError::EncryptionError)?;
+    Ok(Stanza {
+        kind: STANZA_KEY,
+        body: [keyfile::key_id(key).as_slice(), &salt, &wrapped].concat(),
+    })
+}
+
+/// Id of the symmetric key `stanza` was wrapped with, if it is a symmetric stanza
+pub fn stanza_key_id(stanza: &Stanza) -> Option<[u8; KEY_ID_LEN]> {
+    match stanza.kind {
+        STANZA_KEY => stanza.body.get(..KEY_ID_LEN)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Unwraps the file key from `stanza` if it was wrapped with `key`
+pub fn unwrap_key(stanza: &Stanza, key: &Key) -> Option<Key> {
+    if stanza.kind != STANZA_KEY
+        || stanza.body.len() != KEY_ID_LEN + WRAP_SALT_LEN + KEY_LEN + TAG_LEN
+    {
+        return None;
+    }
+    let (key_id, rest) = stanza.body.split_at(KEY_ID_LEN);
+    if key_id != keyfile::key_id(key) {
+        return None;
+    }
+    let (salt, wrapped) = rest.split_at(WRAP_SALT_LEN);
+    let file_key = ChaCha20Poly1305::new(&symmetric_wrapping_key(key, salt))
+        .decrypt(Nonce::from_slice(&[0; 12]), wrapped)
+        .ok()?;
+    Some(*Key::from_slice(&file_key))
+}
+
+/// Wraps `file_key` to an SSH key
+///
+/// The stanza body is the tag of the SSH key, the ephemeral X25519 public key
+/// for ed25519 keys, and the encrypted file key.
+fn wrap_ssh(file_key: &Key, recipient: &SshRecipient) -> Result<Stanza, CliError> {
+    let wrapped = recipient.wrap(file_key, &format!("crypt {}", recipient.kind()))?;
+    let kind = match wrapped.share {
+        Some(_) => STANZA_SSH_ED25519,
+        None => STANZA_SSH_RSA,
+    };
+    let share = wrapped
+        .share
+        .as_ref()
+        .map_or(&[][..], |share| share.as_slice());
+    Ok(Stanza {
+        kind,
+        body: [wrapped.tag.as_slice(), share, &wrapped.body].concat(),
+    })
+}
+
+/// Unwraps the file key from `stanza` if it was wrapped to the SSH key `identity`
+fn unwrap_ssh(stanza: &Stanza, identity: &SshIdentity) -> Option<Key> {
+    let (tag, rest) = stanza.body.split_first_chunk::<KEY_TAG_LEN>()?;
+    let wrapped = match stanza.kind {
+        STANZA_SSH_ED25519 if rest.len() == KEY_LEN + KEY_LEN + TAG_LEN => {
+            let (share, body) = rest.split_at(KEY_LEN);
+            SshWrapped {
+                tag: *tag,
+                share: Some(share.try_into().ok()?),
+                body: body.to_vec(),
+            }
+        }
+        STANZA_SSH_RSA => SshWrapped {
+            tag: *tag,
+            share: None,
+            body: rest.to_vec(),
+        },
+        _ => return None,
+    };
+    let file_key = identity.unwrap(&wrapped, &format!("crypt {}", wrapped.kind()))?;
+    Some(Key::clone_from_slice(file_key.get(..KEY_LEN)?))
+}
+
+/// Public key a file can be encrypted to
+pub enum Recipient {
+    X25519(PublicKey),
+    Ssh(SshRecipient),
+}
+
+impl Recipient {
+    /// Decodes an X25519 public key (typed or `age1...`) or an SSH public key line
+    pub fn decode(text: &str) -> Result<Recipient, CliError> {
+        let is_ssh = text.split_whitespace().any(ssh::is_key_type);
+        match is_ssh {
+            true => Ok(Recipient::Ssh(SshRecipient::parse(text)?)),
+            false => Ok(Recipient::X25519(decode_public(text)?)),
+        }
+    }
+
+    /// Decodes a file with one recipient per line, such as `authorized_keys`, ignoring blank
+    /// and `#` comment lines. Keys of unsupported types are skipped and returned by type
+    /// alongside the recipients.
+    pub fn decode_file(data: &[u8]) -> Result<(Vec<Recipient>, Vec<String>), CliError> {
+        let mut recipients = Vec::new();
+        let mut skipped = Vec::new();
+        for line in keyfile::text(data)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            match Recipient::decode(line) {
+                Ok(recipient) => recipients.push(recipient),
+                Err(CliError::UnsupportedKeyType(kind)) => skipped.push(kind),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok((recipients, skipped))
+    }
+
+    pub fn wrap(&self, file_key: &Key) -> Result<Stanza, CliError> {
+        match self {
+            Recipient::X25519(public) => wrap_x25519(file_key, public),
+            Recipient::Ssh(recipient) => wrap_ssh(file_key, recipient),
+        }
+    }
+}
+
+/// Private key a file can be decrypted with
+pub enum Identity {
+    X25519(StaticSecret),
+    Ssh(SshIdentity),
+}
+
+impl Identity {
+    /// Decodes an X25519 identity file (typed or age) or an unencrypted SSH private key
+    pub fn decode(data: &[u8]) -> Result<Identity, CliError> {
+        let text = keyfile::text(data)?;
+        match SshIdentity::detect(text) {
+            true => Ok(Identity::Ssh(SshIdentity::parse(text)?)),
+            false => Ok(Identity::X25519(decode_identity(data)?)),
+        }
+    }
+
+    /// Unwraps the file key from `stanza` if it was wrapped to this identity
+    pub fn unwrap(&self, stanza: &Stanza) -> Option<Key> {
+        match self {
+            Identity::X25519(identity) => unwrap_x25519(stanza, identity),
+            Identity::Ssh(identity) => unwrap_ssh(stanza, identity),
+        }
+    }
+}
+
+fn header_mac_state(file_key: &Key) -> Hmac<Sha256> {
+    let mut mac_key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, file_key)
+        .expand(b"crypt header", &mut mac_key)
+        .expect("key length is valid for HKDF-SHA256");
+    Hmac::<Sha256>::new_from_slice(&mac_key).expect("HMAC accepts keys of any length")
+}
+
+/// MAC binding the stanzas to the rest of the header, keyed by the payload key
+pub fn header_mac(file_key: &Key, header: &Header) -> [u8; MAC_LEN] {
+    let mut mac = header_mac_state(file_key);
+    mac.update(&header.to_bytes_unauthenticated());
+    mac.finalize().into_bytes().into()
+}
+
+pub fn verify_header_mac(file_key: &Key, header: &Header) -> Result<(), CliError> {
+    let mut mac = header_mac_state(file_key);
+    mac.update(&header.to_bytes_unauthenticated());
+    mac.verify_slice(&header.mac)
+        .map_err(|_| CliError::HeaderAuthError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ED25519_LINE: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIDBsIocERSKwGzc6Q2TVfmuEZxMlg2hNqU37Ay1quxUz ed";
+    const ECDSA_LINE: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBLln7jEduitIq6CG2lZlkc/3H+Cnm4yj6Pmbmhs9gQBOuVcRf/6z1WwJGWYZyYcPxC53i6GEYu5tCcLUdxk0b0o= ec";
+
+    #[test]
+    fn decode_file_skips_unsupported_key_types() {
+        let data = format!("# keys\n{}\n\n{}\n", ECDSA_LINE, ED25519_LINE);
+        let (recipients, skipped) = Recipient::decode_file(data.as_bytes()).unwrap();
+        assert!(matches!(recipients[..], [Recipient::Ssh(_)]));
+        assert_eq!(skipped, ["ecdsa-sha2-nistp256"]);
+    }
+
+    #[test]
+    fn decode_file_rejects_malformed_keys() {
+        let data = format!("{}\nssh-ed25519 AAAA\n", ED25519_LINE);
+        assert!(matches!(
+            Recipient::decode_file(data.as_bytes()),
+            Err(CliError::KeyDecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn decode_rejects_unsupported_key_type() {
+        assert!(matches!(
+            Recipient::decode(ECDSA_LINE),
+            Err(CliError::UnsupportedKeyType(kind)) if kind == "ecdsa-sha2-nistp256"
+        ));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::keyfile::{self, KEY_ID_LEN};
+use crate::{CliError, KEY_LEN};
+use chacha20poly1305::Key;
+use rand::prelude::*;
+
+/// Prefix of an encoded share
+const SHARE_PREFIX: &str = "crypt-share-v1";
+
+/// One share of a key split with Shamir's secret sharing
+pub struct Share {
+    /// Key id of the key the share belongs to
+    pub key_id: [u8; KEY_ID_LEN],
+    /// Number of shares needed to recover the key
+    pub threshold: u8,
+    /// Point at which the polynomials were evaluated, never zero
+    pub index: u8,
+    pub value: [u8; KEY_LEN],
+}
+
+/// Multiplies in GF(2^8) with the AES reduction polynomial `x^8 + x^4 + x^3 + x + 1`
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Inverts a non-zero element of GF(2^8) as `a^254`
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp != 0 {
+        if exp & 1 != 0 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+/// Splits `key` into `shares` shares any `threshold` of which recover it, each byte of the key
+/// being the constant term of its own random polynomial of degree `threshold - 1`
+pub fn split(key: &Key, threshold: u8, shares: u8) -> Result<Vec<Share>, CliError> {
+    if threshold == 0 || threshold > shares {
+        return Err(CliError::InvalidThreshold(threshold, shares));
+    }
+
+    let mut rng = rand::thread_rng();
+    let coefficients: Vec<[u8; KEY_LEN]> = (1..threshold).map(|_| rng.gen()).collect();
+    let key_id = keyfile::key_id(key);
+    Ok((1..=shares)
+        .map(|index| {
+            let mut value = [0u8; KEY_LEN];
+            for (i, byte) in value.iter_mut().enumerate() {
+                // Horner's rule from the highest coefficient down to the key byte
+                *byte = coefficients
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, c| gf_mul(acc, index) ^ c[i]);
+                *byte = gf_mul(*byte, index) ^ key[i];
+            }
+            Share {
+                key_id,
+                threshold,
+                index,
+                value,
+            }
+        })
+        .collect())
+}
+
+/// Recovers the key from at least `threshold` shares of it by Lagrange interpolation at zero
+pub fn combine(shares: &[Share]) -> Result<Key, CliError> {
+    let first = shares.first().ok_or(CliError::NotEnoughShares(0, 1))?;
+    if shares
+        .iter()
+        .any(|s| s.key_id != first.key_id || s.threshold != first.threshold)
+    {
+        return Err(CliError::ShareMismatch);
+    }
+    let mut indexes: Vec<u8> = shares.iter().map(|s| s.index).collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    if indexes.len() < first.threshold as usize {
+        return Err(CliError::NotEnoughShares(indexes.len(), first.threshold));
+    }
+
+    let shares: Vec<&Share> = indexes
+        .iter()
+        .take(first.threshold as usize)
+        .filter_map(|&index| shares.iter().find(|s| s.index == index))
+        .collect();
+    let mut key = [0u8; KEY_LEN];
+    for share in &shares {
+        // Lagrange basis polynomial of this share evaluated at zero, where subtraction is xor
+        let basis = shares
+            .iter()
+            .filter(|other| other.index != share.index)
+            .fold(1, |acc, other| {
+                gf_mul(acc, gf_mul(other.index, gf_inv(other.index ^ share.index)))
+            });
+        for (byte, value) in key.iter_mut().zip(share.value) {
+            *byte ^= gf_mul(basis, value);
+        }
+    }
+
+    let key = Key::from(key);
+    match keyfile::key_id(&key) == first.key_id {
+        true => Ok(key),
+        false => Err(CliError::ShareMismatch),
+    }
+}
+
+/// Encodes a share as `crypt-share-v1:<key id>:<threshold>:<index>:<base64 value>:<checksum>`
+pub fn encode(share: &Share) -> String {
+    keyfile::encode_typed(
+        SHARE_PREFIX,
+        &[
+            hex::encode(share.key_id),
+            share.threshold.to_string(),
+            share.index.to_string(),
+            keyfile::encode_base64(&share.value),
+        ],
+    )
+}
+
+/// Decodes a share written by [`encode`], verifying its checksum
+pub fn decode(data: &[u8]) -> Result<Share, CliError> {
+    let malformed = || CliError::KeyDecodeError("malformed share".to_string());
+    let (key_id, threshold, index, value) =
+        match keyfile::decode_typed_fields(keyfile::text(data)?, SHARE_PREFIX)?[..] {
+            [key_id, threshold, index, value] => (key_id, threshold, index, value),
+            _ => return Err(malformed()),
+        };
+
+    let key_id = hex::decode(key_id)
+        .ok()
+        .and_then(|id| id.try_into().ok())
+        .ok_or_else(malformed)?;
+    let threshold = threshold.parse().map_err(|_| malformed())?;
+    let index = match index.parse() {
+        Ok(0) | Err(_) => return Err(malformed()),
+        Ok(index) => index,
+    };
+    Ok(Share {
+        key_id,
+        threshold,
+        index,
+        value: keyfile::decode_base64_key(value)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::key;
+
+    /// Copies the shares whose bit is set in `mask` through their encoding
+    fn pick(shares: &[Share], mask: u32) -> Vec<Share> {
+        shares
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, share)| decode(encode(share).as_bytes()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn any_threshold_shares_recover_the_key() {
+        let shares = split(&key(7), 3, 5).unwrap();
+        for mask in 0..1u32 << shares.len() {
+            let picked = pick(&shares, mask);
+            match picked.len() {
+                0..=2 => assert!(matches!(
+                    combine(&picked),
+                    Err(CliError::NotEnoughShares(..))
+                )),
+                _ => assert_eq!(combine(&picked).unwrap(), key(7)),
+            }
+        }
+    }
+
+    #[test]
+    fn single_share_threshold() {
+        let shares = split(&key(7), 1, 2).unwrap();
+        assert_eq!(combine(&shares[1..]).unwrap(), key(7));
+    }
+
+    #[test]
+    fn rejects_invalid_threshold() {
+        assert!(split(&key(7), 0, 3).is_err());
+        assert!(split(&key(7), 4, 3).is_err());
+    }
+
+    #[test]
+    fn rejects_shares_of_other_keys() {
+        let mut shares = pick(&split(&key(7), 2, 2).unwrap(), 0b01);
+        shares.extend(pick(&split(&key(3), 2, 2).unwrap(), 0b10));
+        assert!(matches!(combine(&shares), Err(CliError::ShareMismatch)));
+    }
+
+    #[test]
+    fn detects_corrupted_share() {
+        let shares = split(&key(7), 2, 2).unwrap();
+        let mut picked = pick(&shares, 0b11);
+        picked[0].value[0] ^= 1;
+        assert!(matches!(combine(&picked), Err(CliError::ShareMismatch)));
+
+        let mut encoded = encode(&shares[0]).into_bytes();
+        let last = encoded.len() - 1;
+        encoded[last] = if encoded[last] == b'0' { b'1' } else { b'0' };
+        assert!(decode(&encoded).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use crate::cipher::PayloadCipher;
+use crate::CliError;
+use chacha20poly1305::aead::Payload;
+use std::io::{ErrorKind, Read, Write};
+
+/// Default length of a plaintext chunk
+pub const CHUNK_LEN: u32 = 64 * 1024;
+
+/// Largest chunk length accepted from a header, bounding memory use while decrypting
+pub const MAX_CHUNK_LEN: u32 = 16 * 1024 * 1024;
+
+/// Length of the authentication tag appended to every chunk
+pub const TAG_LEN: usize = 16;
+
+/// Bytes of the AEAD nonce taken by the chunk counter and the final-chunk flag
+pub const NONCE_OVERHEAD: usize = 5;
+
+/// Number of positions on either side of a failing chunk searched for the one it was encrypted at
+const REORDER_WINDOW: u32 = 256;
+
+/// Most bytes decrypted while searching for the position of a failing chunk, enough for the
+/// whole window at the default chunk length, which narrows it for larger chunks
+const DIAGNOSE_BUDGET: usize = 4 * REORDER_WINDOW as usize * (CHUNK_LEN as usize + TAG_LEN);
+
+/// Builds the nonce of a chunk as `prefix || counter (32 bit big endian) || last flag`
+fn chunk_nonce(prefix: &[u8], counter: u32, last: bool) -> Vec<u8> {
+    let mut nonce = Vec::with_capacity(prefix.len() + NONCE_OVERHEAD);
+    nonce.extend_from_slice(prefix);
+    nonce.extend_from_slice(&counter.to_be_bytes());
+    nonce.push(last as u8);
+    nonce
+}
+
+/// Number of positions on either side searched for a failing chunk of `chunk_len` bytes, each
+/// tried as a final and a non-final chunk within [`DIAGNOSE_BUDGET`]
+fn reorder_window(chunk_len: usize) -> u32 {
+    (DIAGNOSE_BUDGET / (4 * chunk_len.max(1))).min(REORDER_WINDOW as usize) as u32
+}
+
+/// Reads until `buf` is full or the reader is exhausted, returning the number of bytes read
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Reads the next chunk into `buf`, returning its length and whether it is the final one
+///
+/// One byte of lookahead is kept in `carry` so that a chunk ending exactly at
+/// the end of the input is still recognised as the final chunk.
+pub fn read_chunk<R: Read>(
+    reader: &mut R,
+    buf: &mut [u8],
+    carry: &mut Option<u8>,
+) -> std::io::Result<(usize, bool)> {
+    let mut len = 0;
+    if let Some(byte) = carry.take() {
+        buf[0] = byte;
+        len = 1;
+    }
+    len += read_full(reader, &mut buf[len..])?;
+    if len < buf.len() {
+        return Ok((len, true));
+    }
+
+    let mut peek = [0u8];
+    match read_full(reader, &mut peek)? {
+        0 => Ok((len, true)),
+        _ => {
+            *carry = Some(peek[0]);
+            Ok((len, false))
+        }
+    }
+}
+
+/// STREAM construction over the payload cipher
+///
+/// The input is split into chunks of `chunk_len` bytes, each encrypted with a
+/// nonce derived from its position. Every chunk is authenticated with `aad`,
+/// and the final chunk is marked in its nonce so that truncation at a chunk
+/// boundary is detected. Each encrypted chunk is stored as its length
+/// (4 bytes, little endian) followed by the ciphertext, which lets the reader
+/// tell data following the final chunk apart from a corrupted chunk.
+pub struct Stream<'a> {
+    cipher: &'a dyn PayloadCipher,
+    nonce_prefix: &'a [u8],
+    chunk_len: u32,
+    aad: &'a [u8],
+}
+
+impl<'a> Stream<'a> {
+    pub fn new(
+        cipher: &'a dyn PayloadCipher,
+        nonce_prefix: &'a [u8],
+        chunk_len: u32,
+        aad: &'a [u8],
+    ) -> Self {
+        Stream {
+            cipher,
+            nonce_prefix,
+            chunk_len,
+            aad,
+        }
+    }
+
+    /// Encrypts `reader` into `writer`
+    pub fn encrypt<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        input_name: &str,
+        output_name: &str,
+    ) -> Result<(), CliError> {
+        let mut buf = vec![0u8; self.chunk_len as usize];
+        let mut carry = None;
+        let mut counter: u32 = 0;
+        loop {
+            let (len, last) = read_chunk(reader, &mut buf, &mut carry)
+                .map_err(|_| CliError::FileReadError(input_name.to_string()))?;
+            let ciphertext = self.cipher.encrypt(
+                &chunk_nonce(self.nonce_prefix, counter, last),
+                Payload {
+                    msg: &buf[..len],
+                    aad: self.aad,
+                },
+            )?;
+            writer
+                .write_all(&(ciphertext.len() as u32).to_le_bytes())
+                .and_then(|_| writer.write_all(&ciphertext))
+                .map_err(|_| CliError::FileWriteError(output_name.to_string()))?;
+
+            if last {
+                break;
+            }
+            counter = counter.checked_add(1).ok_or(CliError::EncryptionError)?;
+        }
+        writer
+            .flush()
+            .map_err(|_| CliError::FileWriteError(output_name.to_string()))
+    }
+
+    fn decrypt_chunk(&self, counter: u32, last: bool, chunk: &[u8]) -> Option<Vec<u8>> {
+        self.cipher
+            .decrypt(
+                &chunk_nonce(self.nonce_prefix, counter, last),
+                Payload {
+                    msg: chunk,
+                    aad: self.aad,
+                },
+            )
+            .ok()
+    }
+
+    /// Works out why the chunk read at `counter` failed to decrypt
+    fn diagnose(&self, counter: u32, at_end: bool, chunk: &[u8]) -> CliError {
+        if self.decrypt_chunk(counter, !at_end, chunk).is_some() {
+            return match at_end {
+                true => CliError::Truncated,
+                false => CliError::TrailingData,
+            };
+        }
+
+        for distance in 1..=reorder_window(chunk.len()) {
+            let candidates = [counter.checked_sub(distance), counter.checked_add(distance)];
+            for position in candidates.into_iter().flatten() {
+                if [false, true]
+                    .iter()
+                    .any(|&last| self.decrypt_chunk(position, last, chunk).is_some())
+                {
+                    return CliError::ChunkOutOfOrder(position, counter);
+                }
+            }
+        }
+        CliError::DecryptionError
+    }
+
+    /// Decrypts chunks produced by [`Stream::encrypt`] from `reader` into `writer`
+    ///
+    /// Missing, reordered and trailing chunks are reported as [`CliError::Truncated`],
+    /// [`CliError::ChunkOutOfOrder`] and [`CliError::TrailingData`] respectively.
+    pub fn decrypt<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        input_name: &str,
+        output_name: &str,
+    ) -> Result<(), CliError> {
+        let read_error = |_| CliError::FileReadError(input_name.to_string());
+        let max_len = self.chunk_len as usize + TAG_LEN;
+        let mut buf = vec![0u8; max_len];
+        let mut carry = None;
+        let mut counter: u32 = 0;
+        loop {
+            let mut len_bytes = [0u8; 4];
+            let (read, _) = read_chunk(reader, &mut len_bytes, &mut carry).map_err(read_error)?;
+            if read < len_bytes.len() {
+                return Err(CliError::Truncated);
+            }
+            let len = u32::from_le_bytes(len_bytes) as usize;
+            if !(TAG_LEN..=max_len).contains(&len) {
+                return Err(CliError::DecryptionError);
+            }
+
+            let (read, at_end) =
+                read_chunk(reader, &mut buf[..len], &mut carry).map_err(read_error)?;
+            if read < len {
+                return Err(CliError::Truncated);
+            }
+            let chunk = &buf[..len];
+            let plaintext = self
+                .decrypt_chunk(counter, at_end, chunk)
+                .ok_or_else(|| self.diagnose(counter, at_end, chunk))?;
+            writer
+                .write_all(&plaintext)
+                .map_err(|_| CliError::FileWriteError(output_name.to_string()))?;
+
+            if at_end {
+                break;
+            }
+            counter = counter.checked_add(1).ok_or(CliError::TrailingData)?;
+        }
+        writer
+            .flush()
+            .map_err(|_| CliError::FileWriteError(output_name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::CipherId;
+    use crate::testutil::key;
+    use clap::ArgEnum;
+
+    const PREFIX: [u8; 19] = [5; 19];
+
+    fn encrypt(cipher: CipherId, plaintext: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        let prefix = &PREFIX[..cipher.nonce_prefix_len()];
+        Stream::new(&*cipher.new_cipher(&key(1)), prefix, 16, b"aad")
+            .encrypt(&mut &plaintext[..], &mut encrypted, "in", "out")
+            .unwrap();
+        encrypted
+    }
+
+    fn decrypt(cipher: CipherId, encrypted: &[u8]) -> Result<Vec<u8>, CliError> {
+        let mut decrypted = Vec::new();
+        let prefix = &PREFIX[..cipher.nonce_prefix_len()];
+        Stream::new(&*cipher.new_cipher(&key(1)), prefix, 16, b"aad").decrypt(
+            &mut &encrypted[..],
+            &mut decrypted,
+            "in",
+            "out",
+        )?;
+        Ok(decrypted)
+    }
+
+    /// Splits an encrypted stream into its length prefixed chunks
+    fn chunks(mut encrypted: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        while !encrypted.is_empty() {
+            let len = 4 + u32::from_le_bytes(encrypted[..4].try_into().unwrap()) as usize;
+            chunks.push(encrypted[..len].to_vec());
+            encrypted = &encrypted[len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn round_trip() {
+        for cipher in CipherId::value_variants() {
+            for len in [0, 1, 15, 16, 17, 32, 100] {
+                let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let encrypted = encrypt(*cipher, &plaintext);
+                assert_eq!(decrypt(*cipher, &encrypted).unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn detects_truncation() {
+        let cipher = CipherId::XChaCha20Poly1305;
+        let encrypted = encrypt(cipher, &[0; 40]);
+        let chunks = chunks(&encrypted);
+        assert!(matches!(
+            decrypt(cipher, &chunks[..2].concat()),
+            Err(CliError::Truncated)
+        ));
+        assert!(matches!(
+            decrypt(cipher, &encrypted[..encrypted.len() - 1]),
+            Err(CliError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn detects_trailing_data() {
+        let cipher = CipherId::XChaCha20Poly1305;
+        let encrypted = encrypt(cipher, &[0; 40]);
+        let chunks = chunks(&encrypted);
+        let mut appended = encrypted.clone();
+        appended.extend_from_slice(&chunks[0]);
+        assert!(matches!(
+            decrypt(cipher, &appended),
+            Err(CliError::TrailingData)
+        ));
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let cipher = CipherId::XChaCha20Poly1305;
+        let mut encrypted = encrypt(cipher, &[0; 40]);
+        encrypted[10] ^= 1;
+        assert!(matches!(
+            decrypt(cipher, &encrypted),
+            Err(CliError::DecryptionError)
+        ));
+    }
+
+    #[test]
+    fn detects_reordering() {
+        let cipher = CipherId::XChaCha20Poly1305;
+        let encrypted = encrypt(cipher, &[0; 40]);
+        let chunks = chunks(&encrypted);
+        let swapped = [&chunks[1], &chunks[0], &chunks[2]]
+            .map(Vec::as_slice)
+            .concat();
+        assert!(matches!(
+            decrypt(cipher, &swapped),
+            Err(CliError::ChunkOutOfOrder(1, 0))
+        ));
+    }
+
+    #[test]
+    fn bounds_diagnosis_work() {
+        assert_eq!(reorder_window(CHUNK_LEN as usize + TAG_LEN), REORDER_WINDOW);
+        assert_eq!(reorder_window(1024), REORDER_WINDOW);
+        let len = MAX_CHUNK_LEN as usize + TAG_LEN;
+        assert!(4 * reorder_window(len) as usize * len <= DIAGNOSE_BUDGET);
+    }
+}
@@ -0,0 +1,19 @@
+//! Helpers shared by the unit tests
+
+use crate::KEY_LEN;
+use chacha20poly1305::Key;
+
+/// Fixed key whose bytes count up by `step`, different steps giving different keys
+pub fn key(step: u8) -> Key {
+    Key::from(std::array::from_fn::<u8, KEY_LEN, _>(|i| {
+        (i as u8).wrapping_mul(step)
+    }))
+}
+
+/// Empty directory under the temporary directory, unique to `name` and this test run
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("crypt-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
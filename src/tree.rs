@@ -0,0 +1,55 @@
+use std::path::{Path, PathBuf};
+
+/// A file found below a directory
+pub enum Entry {
+    /// Regular file to encrypt or decrypt
+    File(PathBuf),
+    /// File left alone, with the reason why
+    Skipped(PathBuf, &'static str),
+    /// Directory whose entries could not be listed
+    Unreadable(PathBuf),
+}
+
+/// Lists the files below `dir` depth first and in name order, skipping `exclude` when it lies
+/// inside so that an output directory is not walked into. Symbolic links are skipped rather
+/// than followed, so that files outside `dir` are never touched and loops cannot occur.
+pub fn walk(dir: &Path, exclude: Option<&Path>) -> Vec<Entry> {
+    let exclude = exclude.and_then(|exclude| std::fs::canonicalize(exclude).ok());
+    let mut entries = Vec::new();
+    walk_into(dir, exclude.as_deref(), &mut entries);
+    entries
+}
+
+fn walk_into(dir: &Path, exclude: Option<&Path>, entries: &mut Vec<Entry>) {
+    let mut paths: Vec<PathBuf> = match std::fs::read_dir(dir)
+        .and_then(|read_dir| read_dir.map(|entry| Ok(entry?.path())).collect())
+    {
+        Ok(paths) => paths,
+        Err(_) => return entries.push(Entry::Unreadable(dir.to_path_buf())),
+    };
+    paths.sort();
+
+    for path in paths {
+        let file_type = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata.file_type(),
+            Err(_) => {
+                entries.push(Entry::Unreadable(path));
+                continue;
+            }
+        };
+        if file_type.is_symlink() {
+            entries.push(Entry::Skipped(path, "symbolic link"));
+        } else if file_type.is_dir() {
+            let excluded = exclude.is_some_and(|exclude| {
+                std::fs::canonicalize(&path).is_ok_and(|path| path == exclude)
+            });
+            if !excluded {
+                walk_into(&path, exclude, entries);
+            }
+        } else if file_type.is_file() {
+            entries.push(Entry::File(path));
+        } else {
+            entries.push(Entry::Skipped(path, "not a regular file"));
+        }
+    }
+}
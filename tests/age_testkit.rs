@@ -0,0 +1,99 @@
+//! Runs `crypt de --format age` against the age test vectors of the age 0.10.1 crate, see
+//! <https://github.com/C2SP/CCTV/tree/main/age>
+
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const TESTKIT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/testdata/testkit");
+
+/// A test vector: `key: value` lines, an empty line and the file to decrypt
+struct Vector {
+    expect: String,
+    payload: Option<String>,
+    identities: Vec<String>,
+    passphrase: Option<String>,
+    file: Vec<u8>,
+}
+
+impl Vector {
+    fn parse(data: &[u8]) -> Vector {
+        let split = data
+            .windows(2)
+            .position(|window| window == b"\n\n")
+            .expect("vector has a header");
+        let mut vector = Vector {
+            expect: String::new(),
+            payload: None,
+            identities: Vec::new(),
+            passphrase: None,
+            file: data[split + 2..].to_vec(),
+        };
+        for line in std::str::from_utf8(&data[..split]).unwrap().lines() {
+            let (key, value) = line.split_once(": ").expect("header line is `key: value`");
+            match key {
+                "expect" => vector.expect = value.to_string(),
+                "payload" => vector.payload = Some(value.to_string()),
+                "identity" => vector.identities.push(value.to_string()),
+                "passphrase" => vector.passphrase = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        vector
+    }
+}
+
+/// Decrypts `vector` to standard output, returning whether it succeeded and the output
+fn decrypt(vector: &Vector, dir: &Path) -> (bool, Vec<u8>) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_crypt"));
+    command.args(["de", "--format", "age", "--input", "-"]);
+    for (i, identity) in vector.identities.iter().enumerate() {
+        let path = dir.join(format!("identity{}", i));
+        std::fs::write(&path, format!("{}\n", identity)).unwrap();
+        command.arg("--identity").arg(path);
+    }
+    if let (Some(passphrase), true) = (&vector.passphrase, vector.identities.is_empty()) {
+        command.arg(format!("--password={}", passphrase));
+    }
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // The file may be rejected before it has been read entirely
+    let _ = child.stdin.take().unwrap().write_all(&vector.file);
+    let output = child.wait_with_output().unwrap();
+    (output.status.success(), output.stdout)
+}
+
+#[test]
+fn testkit() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("crypt-testkit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(TESTKIT)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let vector = Vector::parse(&std::fs::read(path).unwrap());
+        let (success, output) = decrypt(&vector, &dir);
+        let passed = match vector.expect.as_str() {
+            "success" => success && vector.payload == Some(hex::encode(Sha256::digest(&output))),
+            _ => !success,
+        };
+        if !passed {
+            failures.push(path.file_name().unwrap().to_string_lossy().into_owned());
+        }
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(failures.is_empty(), "failed vectors: {:?}", failures);
+}